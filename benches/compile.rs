@@ -0,0 +1,63 @@
+// Times compiling a generated script of about 8.5k lines with the interpreter binary.
+// The functions in it are never called, so running it only defines them. Starting the
+// interpreter is timed on an empty script and subtracted.
+//
+// Run with `cargo bench --bench compile`.
+use std::{fs, path::Path, process::Command, time::Instant};
+
+// Split over functions and blocks, a chunk only has room for 256 constants.
+const SNIPPET: &str = r#"{
+class Point {
+    init(x, y) { this.x = x; this.y = y; }
+    length_squared() { return this.x * this.x + this.y * this.y; }
+}
+fun fib(n) {
+    if (n < 2) return n;
+    return fib(n - 1) + fib(n - 2);
+}
+for (let i = 0; i < 10; i = i + 1) {
+    let p = Point(i, i * 2);
+    let items = [1, 2.5, 0xff, "text ${i}", {"key": p.length_squared()}];
+    if (i % 2 == 0 and !(i > 5) or i == 9) { print fib(i) ** 2 ~/ 3; }
+}
+}"#;
+const RUNS: u32 = 20;
+
+// The interpreter reads scripts from the runnables folder.
+fn time_script(name: &str, source: &str) -> f64 {
+    let path = Path::new("runnables").join(name);
+    fs::write(&path, source).unwrap();
+
+    let start = Instant::now();
+    for _ in 0..RUNS {
+        let output = Command::new(env!("CARGO_BIN_EXE_lumi_v2"))
+            .arg(name)
+            .output()
+            .unwrap();
+        assert!(
+            output.stderr.is_empty(),
+            "{}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+    }
+    let seconds = start.elapsed().as_secs_f64() / RUNS as f64;
+
+    fs::remove_file(&path).unwrap();
+    seconds
+}
+
+fn main() {
+    let source: String = (0..120)
+        .map(|i| format!("fun bench{}() {{{}}}\n", i, SNIPPET.repeat(5)))
+        .collect();
+
+    let startup = time_script("bench_empty.lumi", "");
+    let seconds = time_script("bench_compile.lumi", &source) - startup;
+    println!(
+        "Compiled {} lines in {:.2}ms, {:.1} MB/s (startup {:.2}ms not included)",
+        source.lines().count(),
+        seconds * 1000.0,
+        source.len() as f64 / seconds / 1_000_000.0,
+        startup * 1000.0
+    );
+}
@@ -26,6 +26,42 @@ pub enum OpCode {
     JumpIfFalse,
     Jump,
     Loop,
+    Call,
+    Closure,
+    GetUpvalue,
+    SetUpvalue,
+    CloseUpvalue,
+    Class,
+    GetProperty,
+    SetProperty,
+    Method,
+    Inherit,
+    GetSuper,
+    BuildList,
+    GetIndex,
+    SetIndex,
+    BuildMap,
+    Modulo,
+    IntDivide,
+    Power,
+    BitAnd,
+    BitOr,
+    BitXor,
+    BitNot,
+    ShiftLeft,
+    ShiftRight,
+    Stringify,
+    // Followed by a byte that is 1 when the upper bound is included.
+    InRange,
+    // Followed by the constant holding the lowest case, the number of cases, a jump offset
+    // for every case and one for the default. The offsets count back from the end of the
+    // instruction.
+    JumpTable,
+    DefineFinalGlobal,
+    Increment,
+    Decrement,
+    // Pushes another copy of the value on top of the stack.
+    Dup,
 }
 
 impl OpCode {
@@ -55,6 +91,37 @@ impl OpCode {
             21 => Some(OpCode::JumpIfFalse),
             22 => Some(OpCode::Jump),
             23 => Some(OpCode::Loop),
+            24 => Some(OpCode::Call),
+            25 => Some(OpCode::Closure),
+            26 => Some(OpCode::GetUpvalue),
+            27 => Some(OpCode::SetUpvalue),
+            28 => Some(OpCode::CloseUpvalue),
+            29 => Some(OpCode::Class),
+            30 => Some(OpCode::GetProperty),
+            31 => Some(OpCode::SetProperty),
+            32 => Some(OpCode::Method),
+            33 => Some(OpCode::Inherit),
+            34 => Some(OpCode::GetSuper),
+            35 => Some(OpCode::BuildList),
+            36 => Some(OpCode::GetIndex),
+            37 => Some(OpCode::SetIndex),
+            38 => Some(OpCode::BuildMap),
+            39 => Some(OpCode::Modulo),
+            40 => Some(OpCode::IntDivide),
+            41 => Some(OpCode::Power),
+            42 => Some(OpCode::BitAnd),
+            43 => Some(OpCode::BitOr),
+            44 => Some(OpCode::BitXor),
+            45 => Some(OpCode::BitNot),
+            46 => Some(OpCode::ShiftLeft),
+            47 => Some(OpCode::ShiftRight),
+            48 => Some(OpCode::Stringify),
+            49 => Some(OpCode::InRange),
+            50 => Some(OpCode::JumpTable),
+            51 => Some(OpCode::DefineFinalGlobal),
+            52 => Some(OpCode::Increment),
+            53 => Some(OpCode::Decrement),
+            54 => Some(OpCode::Dup),
             _ => None,
         }
     }
@@ -64,14 +131,14 @@ impl OpCode {
 pub struct Chunk {
     pub code: Vec<u8>,
     pub lines: Vec<i32>,
+    pub columns: Vec<usize>,
     pub constants: ValueArray,
 }
 
 pub trait ChunkWrite {
     fn new() -> Self;
-    fn write_chunk(&mut self, byte: u8, line: i32);
-    fn add_constants(&mut self, value: Value, is_final: bool) -> usize;
-    fn free(&mut self);
+    fn write_chunk(&mut self, byte: u8, line: i32, column: usize);
+    fn add_constants(&mut self, value: Value) -> usize;
 }
 
 impl ChunkWrite for Chunk {
@@ -79,23 +146,19 @@ impl ChunkWrite for Chunk {
         Self {
             code: Vec::new(),
             lines: Vec::new(),
+            columns: Vec::new(),
             constants: ValueArray::new(),
         }
     }
 
-    fn write_chunk(&mut self, byte: u8, line: i32) {
+    fn write_chunk(&mut self, byte: u8, line: i32, column: usize) {
         self.code.push(byte);
         self.lines.push(line);
+        self.columns.push(column);
     }
 
-    fn add_constants(&mut self, value: Value, is_final: bool) -> usize {
-        self.constants.write_value(value, is_final);
+    fn add_constants(&mut self, value: Value) -> usize {
+        self.constants.write_value(value);
         self.constants.len() - 1
     }
-
-    fn free(&mut self) {
-        self.code.clear();
-        self.lines.clear();
-        self.constants.free();
-    }
 }
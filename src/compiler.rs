@@ -2,13 +2,15 @@ use core::str;
 use std::collections::HashMap;
 
 #[cfg(feature = "trace_exec")]
-use crate::debug::disassemble_instruction;
+use crate::debug::disassemble_chunk;
 use crate::{
     chunk::{Chunk, ChunkWrite, OpCode},
-    core::Table,
-    object::{Obj, ObjString},
-    scanner::{Scanner, Token, TokenType},
-    utils::strtod_manual,
+    diagnostic::{Diagnostic, ErrorCode},
+    lnum::{LInt, LNum},
+    memory::Heap,
+    object::{Obj, ObjFunction, ObjRef, ObjString},
+    scanner::{Scanner, Span, Token, TokenType},
+    utils::{strip_indentation, strtod_manual, unescape},
     value::Value,
 };
 
@@ -16,26 +18,122 @@ use crate::{
 enum Precedence {
     None,
     Assignment,
+    // The ternary 'a ? b : c'.
+    Conditional,
+    // 'a ?? b' and 'a ?: b'.
+    Coalesce,
     Or,
     And,
     Equality,
     Comparison,
+    BitOr,
+    BitXor,
+    BitAnd,
+    Shift,
     Term,
     Factor,
     Unary,
+    // Binds tighter than unary, so -2 ** 2 is -(2 ** 2).
+    Power,
     Call,
     Primary,
 }
 
-type ParseFn<'a> = Option<fn(&mut Compiler<'a>)>;
+type ParseFn = Option<for<'a> fn(&mut Compiler<'a>)>;
 
 #[derive(Debug, Clone, Copy)]
-struct ParseRule<'a> {
-    prefix: ParseFn<'a>,
-    infix: ParseFn<'a>,
+struct ParseRule {
+    prefix: ParseFn,
+    infix: ParseFn,
     precedence: Precedence,
 }
 
+impl ParseRule {
+    const fn new(prefix: ParseFn, infix: ParseFn, precedence: Precedence) -> Self {
+        Self {
+            prefix,
+            infix,
+            precedence,
+        }
+    }
+}
+
+// The parse rules of the Pratt parser, indexed by TokenType. Tokens without an entry can't
+// start or continue an expression.
+static RULES: [ParseRule; TokenType::COUNT] = {
+    let mut rules = [ParseRule::new(None, None, Precedence::None); TokenType::COUNT];
+    rules[TokenType::LeftParen as usize] =
+        ParseRule::new(Some(|c| c.grouping()), Some(|c| c.call()), Precedence::Call);
+    rules[TokenType::LeftBrace as usize] =
+        ParseRule::new(Some(|c| c.map()), None, Precedence::None);
+    rules[TokenType::LeftBracket as usize] =
+        ParseRule::new(Some(|c| c.list()), Some(|c| c.index()), Precedence::Call);
+    rules[TokenType::Dot as usize] = ParseRule::new(None, Some(|c| c.dot()), Precedence::Call);
+    rules[TokenType::Minus as usize] =
+        ParseRule::new(Some(|c| c.unary()), Some(|c| c.binary()), Precedence::Term);
+    rules[TokenType::Plus as usize] = ParseRule::new(None, Some(|c| c.binary()), Precedence::Term);
+    rules[TokenType::PlusPlus as usize] =
+        ParseRule::new(Some(|c| c.prefix_increment()), None, Precedence::None);
+    rules[TokenType::MinusMinus as usize] =
+        ParseRule::new(Some(|c| c.prefix_increment()), None, Precedence::None);
+    rules[TokenType::Slash as usize] =
+        ParseRule::new(None, Some(|c| c.binary()), Precedence::Factor);
+    rules[TokenType::Star as usize] =
+        ParseRule::new(None, Some(|c| c.binary()), Precedence::Factor);
+    rules[TokenType::StarStar as usize] =
+        ParseRule::new(None, Some(|c| c.binary()), Precedence::Power);
+    rules[TokenType::Percent as usize] =
+        ParseRule::new(None, Some(|c| c.binary()), Precedence::Factor);
+    rules[TokenType::TildeSlash as usize] =
+        ParseRule::new(None, Some(|c| c.binary()), Precedence::Factor);
+    rules[TokenType::Tilde as usize] = ParseRule::new(Some(|c| c.unary()), None, Precedence::None);
+    rules[TokenType::Ampersand as usize] =
+        ParseRule::new(None, Some(|c| c.binary()), Precedence::BitAnd);
+    rules[TokenType::Pipe as usize] = ParseRule::new(None, Some(|c| c.binary()), Precedence::BitOr);
+    rules[TokenType::Caret as usize] =
+        ParseRule::new(None, Some(|c| c.binary()), Precedence::BitXor);
+    rules[TokenType::LessLess as usize] =
+        ParseRule::new(None, Some(|c| c.binary()), Precedence::Shift);
+    rules[TokenType::GreaterGreater as usize] =
+        ParseRule::new(None, Some(|c| c.binary()), Precedence::Shift);
+    rules[TokenType::Bang as usize] = ParseRule::new(Some(|c| c.unary()), None, Precedence::None);
+    rules[TokenType::BangEqual as usize] =
+        ParseRule::new(None, Some(|c| c.binary()), Precedence::Equality);
+    rules[TokenType::EqualEqual as usize] =
+        ParseRule::new(None, Some(|c| c.binary()), Precedence::Equality);
+    rules[TokenType::Greater as usize] =
+        ParseRule::new(None, Some(|c| c.binary()), Precedence::Comparison);
+    rules[TokenType::GreaterEqual as usize] =
+        ParseRule::new(None, Some(|c| c.binary()), Precedence::Comparison);
+    rules[TokenType::Less as usize] =
+        ParseRule::new(None, Some(|c| c.binary()), Precedence::Comparison);
+    rules[TokenType::LessEqual as usize] =
+        ParseRule::new(None, Some(|c| c.binary()), Precedence::Comparison);
+    rules[TokenType::Identifier as usize] =
+        ParseRule::new(Some(|c| c.variable()), None, Precedence::None);
+    rules[TokenType::Interpolation as usize] =
+        ParseRule::new(Some(|c| c.interpolation()), None, Precedence::None);
+    rules[TokenType::String as usize] =
+        ParseRule::new(Some(|c| c.string()), None, Precedence::None);
+    rules[TokenType::Number as usize] =
+        ParseRule::new(Some(|c| c.number()), None, Precedence::None);
+    rules[TokenType::And as usize] = ParseRule::new(None, Some(|c| c.and()), Precedence::And);
+    rules[TokenType::False as usize] =
+        ParseRule::new(Some(|c| c.literal()), None, Precedence::None);
+    rules[TokenType::Nil as usize] = ParseRule::new(Some(|c| c.literal()), None, Precedence::None);
+    rules[TokenType::Or as usize] = ParseRule::new(None, Some(|c| c.or()), Precedence::Or);
+    rules[TokenType::Question as usize] =
+        ParseRule::new(None, Some(|c| c.conditional()), Precedence::Conditional);
+    rules[TokenType::QuestionQuestion as usize] =
+        ParseRule::new(None, Some(|c| c.nil_coalesce()), Precedence::Coalesce);
+    rules[TokenType::QuestionColon as usize] =
+        ParseRule::new(None, Some(|c| c.elvis()), Precedence::Coalesce);
+    rules[TokenType::Super as usize] = ParseRule::new(Some(|c| c.super_()), None, Precedence::None);
+    rules[TokenType::This as usize] = ParseRule::new(Some(|c| c.this()), None, Precedence::None);
+    rules[TokenType::True as usize] = ParseRule::new(Some(|c| c.literal()), None, Precedence::None);
+    rules
+};
+
 #[derive(Debug, Clone)]
 struct Parser<'a> {
     current: Token<'a>,
@@ -45,37 +143,197 @@ struct Parser<'a> {
 }
 
 const MAX_LOCALS: u8 = u8::MAX;
+const MAX_ARGS: usize = u8::MAX as usize;
+const UINT8_COUNT: usize = u8::MAX as usize + 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum FunctionType {
+    Function,
+    Initializer,
+    Method,
+    Script,
+}
 
+// Holds the state of the function that is currently being compiled.
+// Every nested function declaration gets its own TinyCompiler, the outer one is kept in enclosing.
 #[derive(Debug)]
 struct TinyCompiler<'a> {
+    enclosing: Option<Box<TinyCompiler<'a>>>,
+    function: ObjFunction,
+    function_type: FunctionType,
     // locals: [Local<'a>; MAX_LOCALS as usize],
     locals: Vec<Local<'a>>,
     local_count: usize,
+    upvalues: Vec<Upvalue>,
     scope_depth: usize,
-    is_final: bool,
+    // The loops around the code being compiled, innermost last.
+    loops: Vec<LoopContext<'a>>,
 }
 
 impl<'a> TinyCompiler<'a> {
-    pub fn new() -> Self {
+    pub fn new(function_type: FunctionType) -> Self {
         Self {
-            locals: Vec::new(),
-            local_count: 0,
+            enclosing: None,
+            function: ObjFunction::new(),
+            function_type,
+            // Stack slot zero is claimed by the function that is being called.
+            // Methods name it 'this' so the receiver can be read like any other local.
+            locals: match function_type {
+                FunctionType::Method | FunctionType::Initializer => {
+                    vec![Local::new(synthetic_token("this"), 0)]
+                }
+                FunctionType::Function | FunctionType::Script => {
+                    vec![Local::new(Token::default(), 0)]
+                }
+            },
+            local_count: 1,
+            upvalues: Vec::new(),
             scope_depth: 0,
-            is_final: false,
+            loops: Vec::new(),
+        }
+    }
+
+    fn resolve_local(&self, name: &Token) -> Option<usize> {
+        // Walk backwards so that a local in an inner scope shadows one with the same name further out.
+        (0..self.local_count)
+            .rev()
+            .find(|i| identifiers_equal(name, &self.locals[*i].name))
+    }
+
+    // Looks for the variable in the enclosing functions, capturing it along the way
+    // so every function in between has an upvalue to pass down.
+    fn resolve_upvalue(&mut self, name: &Token) -> Result<Option<u8>, &'static str> {
+        let enclosing = match self.enclosing.as_mut() {
+            Some(enclosing) => enclosing,
+            None => return Ok(None),
+        };
+
+        if let Some(local) = enclosing.resolve_local(name) {
+            enclosing.locals[local].is_captured = true;
+            let is_final = enclosing.locals[local].is_final;
+            return self.add_upvalue(local as u8, true, is_final).map(Some);
+        }
+
+        if let Some(upvalue) = enclosing.resolve_upvalue(name)? {
+            let is_final = enclosing.upvalues[upvalue as usize].is_final;
+            return self.add_upvalue(upvalue, false, is_final).map(Some);
+        }
+
+        Ok(None)
+    }
+
+    fn add_upvalue(
+        &mut self,
+        index: u8,
+        is_local: bool,
+        is_final: bool,
+    ) -> Result<u8, &'static str> {
+        if let Some(existing) = self
+            .upvalues
+            .iter()
+            .position(|u| u.index == index && u.is_local == is_local)
+        {
+            return Ok(existing as u8);
+        }
+
+        if self.upvalues.len() == UINT8_COUNT {
+            return Err("Too many closure variables in function.");
         }
+
+        self.upvalues.push(Upvalue {
+            index,
+            is_local,
+            is_final,
+        });
+        self.function.upvalue_count = self.upvalues.len();
+        Ok((self.upvalues.len() - 1) as u8)
     }
 }
 
+// What 'break' and 'continue' need to know about a loop they're in.
+#[derive(Debug)]
+struct LoopContext<'a> {
+    label: Option<Token<'a>>,
+    // Where 'continue' jumps back to, the condition of a while loop or the increment of a for loop.
+    start: usize,
+    // Locals declared deeper than this are popped when jumping out of the body.
+    scope_depth: usize,
+    // Patched to jump past the end of the loop once it's compiled.
+    break_jumps: Vec<usize>,
+}
+
+// A pattern of a match arm, its values are already in the chunk's constants.
+#[derive(Debug, Clone, Copy)]
+enum Pattern {
+    Literal(u8),
+    Range {
+        lower: u8,
+        upper: u8,
+        inclusive: bool,
+    },
+}
+
+#[derive(Debug)]
+struct MatchArm {
+    patterns: Vec<Pattern>,
+    // Where the arm's code starts, the dispatch after the arms jumps back to it.
+    start: usize,
+    // The jump taken when the arm's guard fails, it continues the dispatch with the next arm.
+    guard_failed: Option<usize>,
+}
+
 #[derive(Debug)]
 struct Local<'a> {
     name: Token<'a>,
     depth: i8,
+    is_captured: bool,
+    is_final: bool,
 }
 
 impl<'a> Local<'a> {
     pub fn new(name: Token<'a>, depth: i8) -> Self {
-        Self { name, depth }
+        Self {
+            name,
+            depth,
+            is_captured: false,
+            is_final: false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Upvalue {
+    index: u8,
+    is_local: bool,
+    // Copied from the variable it captures, so closures can't assign to a final local either.
+    is_final: bool,
+}
+
+// Keeps track of the class whose body is being compiled, so 'this' can be rejected outside of one.
+#[derive(Debug)]
+struct ClassCompiler {}
+
+fn synthetic_token(text: &str) -> Token<'_> {
+    Token {
+        token_type: TokenType::Identifier,
+        start: text.as_bytes(),
+        length: text.len(),
+        line: 0,
+        column: 0,
+        span: Span::default(),
+    }
+}
+
+fn token_text(token: &Token) -> String {
+    String::from_utf8_lossy(&token.start[..token.length]).into_owned()
+}
+
+fn identifiers_equal(a: &Token, b: &Token) -> bool {
+    if a.length != b.length {
+        return false;
     }
+
+    a.start[..a.length] == b.start[..b.length]
 }
 
 impl<'a> Parser<'a> {
@@ -89,15 +347,21 @@ impl<'a> Parser<'a> {
     }
 }
 
-#[derive(Debug)]
 pub struct Compiler<'a> {
     parser: Parser<'a>,
     scanner: Scanner<'a>,
     current: TinyCompiler<'a>,
-    pub chunk: Chunk,
-    pub strings: Table,
-    pub globals: Table,
+    class_compilers: Vec<ClassCompiler>,
+    heap: &'a mut Heap,
+    // Marks the roots the vm owns, for collections that run while compiling.
+    mark_vm_roots: &'a dyn Fn(&mut Heap),
     can_assign: bool,
+    diagnostics: Vec<Diagnostic>,
+    // The globals declared so far in this script and whether they are final.
+    globals: HashMap<String, bool>,
+    // Assignments to globals that weren't declared yet, like in a function declared before
+    // the variable. They are checked once the whole script is compiled.
+    global_assignments: Vec<Token<'a>>,
 }
 
 use std::ops::Add;
@@ -109,52 +373,104 @@ impl Add<u8> for Precedence {
         match self as u8 + other {
             0 => Precedence::None,
             1 => Precedence::Assignment,
-            2 => Precedence::Or,
-            3 => Precedence::And,
-            4 => Precedence::Equality,
-            5 => Precedence::Comparison,
-            6 => Precedence::Term,
-            7 => Precedence::Factor,
-            8 => Precedence::Unary,
-            9 => Precedence::Call,
-            10 => Precedence::Primary,
+            2 => Precedence::Conditional,
+            3 => Precedence::Coalesce,
+            4 => Precedence::Or,
+            5 => Precedence::And,
+            6 => Precedence::Equality,
+            7 => Precedence::Comparison,
+            8 => Precedence::BitOr,
+            9 => Precedence::BitXor,
+            10 => Precedence::BitAnd,
+            11 => Precedence::Shift,
+            12 => Precedence::Term,
+            13 => Precedence::Factor,
+            14 => Precedence::Unary,
+            15 => Precedence::Power,
+            16 => Precedence::Call,
+            17 => Precedence::Primary,
             _ => Precedence::None, // Default case
         }
     }
 }
 
 impl<'a> Compiler<'a> {
-    pub fn new() -> Self {
+    pub fn new(heap: &'a mut Heap, mark_vm_roots: &'a dyn Fn(&mut Heap)) -> Self {
         Self {
             parser: Parser::default(),
             scanner: Scanner::new_empty(),
-            current: TinyCompiler::new(),
-            chunk: Chunk::new(),
-            strings: Table::init(),
-            globals: Table::init(),
+            current: TinyCompiler::new(FunctionType::Script),
+            class_compilers: Vec::new(),
+            heap,
+            mark_vm_roots,
             can_assign: false,
+            diagnostics: Vec::new(),
+            globals: HashMap::new(),
+            global_assignments: Vec::new(),
         }
     }
 
-    pub fn compile(&mut self, code: &'a str) -> bool {
-        self.scanner = Scanner::init_scanner(code.as_bytes());
+    // Returns every error found in the script when it couldn't be compiled.
+    pub fn compile(&mut self, code: &'a str) -> Result<ObjRef, Vec<Diagnostic>> {
+        self.scanner = Scanner::init_scanner(code);
+        self.current = TinyCompiler::new(FunctionType::Script);
+        self.globals.clear();
+        self.global_assignments.clear();
 
-        loop {
-            self.advance();
+        self.advance();
+
+        while !self.matches(TokenType::Eof) {
+            self.declaration();
+        }
+
+        for name in std::mem::take(&mut self.global_assignments) {
+            if self.globals.get(&token_text(&name)) == Some(&true) {
+                self.parser.panic_mode = false;
+                self.final_assignment_error(&name);
+            }
+        }
+
+        let (function, _) = self.end_compiler();
+        if self.parser.had_error {
+            return Err(std::mem::take(&mut self.diagnostics));
+        }
+        Ok(self.alloc(Obj::Function(function)))
+    }
+
+    fn alloc(&mut self, obj: Obj) -> ObjRef {
+        if self.heap.should_collect() {
+            self.collect_garbage(Some(&obj));
+        }
+        self.heap.alloc(obj)
+    }
 
-            while !self.matches(TokenType::Eof) {
-                self.declaration();
+    fn intern(&mut self, chars: &str) -> ObjRef {
+        if self.heap.should_collect() {
+            self.collect_garbage(None);
+        }
+        self.heap.intern(chars)
+    }
+
+    // The functions still being compiled aren't on the heap yet, so their constants are roots.
+    fn collect_garbage(&mut self, allocating: Option<&Obj>) {
+        (self.mark_vm_roots)(self.heap);
+
+        let mut compiler = Some(&self.current);
+        while let Some(current) = compiler {
+            for constant in &current.function.chunk.constants.values {
+                self.heap.mark_value(constant);
             }
-            // self.expression();
-            // self.consume(TokenType::Eof, "Expect end of epxression.".as_bytes());
-            self.end_compiler();
+            compiler = current.enclosing.as_deref();
+        }
 
-            return !self.parser.had_error;
+        if let Some(obj) = allocating {
+            self.heap.mark_references(obj);
         }
+        self.heap.collect();
     }
 
     fn current_chunk(&mut self) -> &mut Chunk {
-        &mut self.chunk
+        &mut self.current.function.chunk
     }
 
     fn advance(&mut self) {
@@ -168,8 +484,8 @@ impl<'a> Compiler<'a> {
                 break;
             }
 
-            let token_clone = token.clone();
-            self.error_at_current(token_clone.start);
+            let message = String::from_utf8_lossy(&token.start[..token.length]).into_owned();
+            self.error_at_current(ErrorCode::InvalidToken, &message);
         }
     }
 
@@ -183,7 +499,8 @@ impl<'a> Compiler<'a> {
             return;
         }
 
-        self.error_at_current(message);
+        let message = String::from_utf8_lossy(message).into_owned();
+        self.error_at_current(ErrorCode::ExpectedToken, &message);
     }
 
     fn check(&self, token_type: TokenType) -> bool {
@@ -195,12 +512,13 @@ impl<'a> Compiler<'a> {
             return false;
         }
         self.advance();
-        return true;
+        true
     }
 
     fn emit_byte(&mut self, byte: u8) {
-        self.chunk
-            .write_chunk(byte, self.parser.previous.line as i32);
+        let line = self.parser.previous.line as i32;
+        let column = self.parser.previous.column;
+        self.current_chunk().write_chunk(byte, line, column);
     }
 
     fn emit_bytes(&mut self, byte1: u8, byte2: u8) {
@@ -213,7 +531,7 @@ impl<'a> Compiler<'a> {
 
         let offset = self.current_chunk().code.len() - loop_start + 2;
         if offset as u16 > u16::MAX {
-            self.error("Loop body too large.".as_bytes());
+            self.error(ErrorCode::TooLarge, "Loop body too large.");
         }
 
         self.emit_byte(((offset >> 8) & 0xff) as u8);
@@ -224,17 +542,23 @@ impl<'a> Compiler<'a> {
         self.emit_byte(instruction);
         self.emit_byte(0xff);
         self.emit_byte(0xff);
-        return self.current_chunk().code.len() - 2;
+        self.current_chunk().code.len() - 2
     }
 
     fn emit_return(&mut self) {
+        if self.current.function_type == FunctionType::Initializer {
+            // An initializer always hands back the instance it initialized.
+            self.emit_bytes(OpCode::GetLocal as u8, 0);
+        } else {
+            self.emit_byte(OpCode::Nil as u8);
+        }
         self.emit_byte(OpCode::Return as u8);
     }
 
     fn make_constant(&mut self, value: Value) -> u8 {
-        let constant = self.chunk.add_constants(value, self.current.is_final);
-        if constant as u8 > u8::MAX {
-            self.error("Too many constants in one chunk.".as_bytes());
+        let constant = self.current_chunk().add_constants(value);
+        if constant > u8::MAX as usize {
+            self.error(ErrorCode::TooLarge, "Too many constants in one chunk.");
             return 0;
         }
 
@@ -249,15 +573,31 @@ impl<'a> Compiler<'a> {
     fn patch_jump(&mut self, offset: usize) {
         let jump = self.current_chunk().code.len() - offset - 2;
         if jump as u16 > u16::MAX {
-            self.error("Too much code to jump over.".as_bytes());
+            self.error(ErrorCode::TooLarge, "Too much code to jump over.");
         }
 
         self.current_chunk().code[offset] = ((jump >> 8) & 0xff) as u8;
         self.current_chunk().code[offset + 1] = (jump & 0xff) as u8;
     }
 
-    fn end_compiler(&mut self) {
+    fn end_compiler(&mut self) -> (ObjFunction, Vec<Upvalue>) {
         self.emit_return();
+
+        #[cfg(feature = "trace_exec")]
+        if !self.parser.had_error {
+            let name = match &self.current.function.name {
+                Some(name) => name.to_string(),
+                None => "<script>".to_string(),
+            };
+            disassemble_chunk(&self.current.function.chunk, &name);
+        }
+
+        let outer = match self.current.enclosing.take() {
+            Some(enclosing) => *enclosing,
+            None => TinyCompiler::new(FunctionType::Script),
+        };
+        let compiler = std::mem::replace(&mut self.current, outer);
+        (compiler.function, compiler.upvalues)
     }
 
     fn begin_scope(&mut self) {
@@ -271,7 +611,11 @@ impl<'a> Compiler<'a> {
             && self.current.locals[self.current.local_count - 1].depth
                 > self.current.scope_depth as i8
         {
-            self.emit_byte(OpCode::Pop as u8);
+            if self.current.locals[self.current.local_count - 1].is_captured {
+                self.emit_byte(OpCode::CloseUpvalue as u8);
+            } else {
+                self.emit_byte(OpCode::Pop as u8);
+            }
             self.current.locals.remove(self.current.local_count - 1);
             self.current.local_count -= 1;
         }
@@ -281,7 +625,12 @@ impl<'a> Compiler<'a> {
         let operator_type = self.parser.previous.token_type.clone();
         let parse_rule = self.get_rule(operator_type.clone());
 
-        self.parse_precedence(parse_rule.precedence + 1);
+        // '**' is right-associative, 2 ** 3 ** 2 is 2 ** (3 ** 2).
+        if operator_type == TokenType::StarStar {
+            self.parse_precedence(parse_rule.precedence);
+        } else {
+            self.parse_precedence(parse_rule.precedence + 1);
+        }
 
         match operator_type {
             TokenType::BangEqual => self.emit_bytes(OpCode::Equal as u8, OpCode::Not as u8),
@@ -294,7 +643,102 @@ impl<'a> Compiler<'a> {
             TokenType::Minus => self.emit_byte(OpCode::Subtract as u8),
             TokenType::Star => self.emit_byte(OpCode::Multiply as u8),
             TokenType::Slash => self.emit_byte(OpCode::Divide as u8),
-            _ => return,
+            TokenType::TildeSlash => self.emit_byte(OpCode::IntDivide as u8),
+            TokenType::Percent => self.emit_byte(OpCode::Modulo as u8),
+            TokenType::StarStar => self.emit_byte(OpCode::Power as u8),
+            TokenType::Ampersand => self.emit_byte(OpCode::BitAnd as u8),
+            TokenType::Pipe => self.emit_byte(OpCode::BitOr as u8),
+            TokenType::Caret => self.emit_byte(OpCode::BitXor as u8),
+            TokenType::LessLess => self.emit_byte(OpCode::ShiftLeft as u8),
+            TokenType::GreaterGreater => self.emit_byte(OpCode::ShiftRight as u8),
+            _ => (),
+        }
+    }
+
+    fn call(&mut self) {
+        let arg_count = self.argument_list();
+        self.emit_bytes(OpCode::Call as u8, arg_count);
+    }
+
+    fn list(&mut self) {
+        let mut count = 0;
+        while !self.check(TokenType::RightBracket) && !self.check(TokenType::Eof) {
+            self.expression();
+            if count == MAX_ARGS {
+                self.error(
+                    ErrorCode::TooLarge,
+                    "Can't have more than 255 elements in a list literal.",
+                );
+            }
+            count += 1;
+
+            if !self.matches(TokenType::Comma) {
+                break;
+            }
+        }
+
+        self.consume(
+            TokenType::RightBracket,
+            "Expect ']' after list elements.".as_bytes(),
+        );
+        self.emit_bytes(OpCode::BuildList as u8, count as u8);
+    }
+
+    fn map(&mut self) {
+        let mut count = 0;
+        while !self.check(TokenType::RightBrace) && !self.check(TokenType::Eof) {
+            self.expression();
+            self.consume(TokenType::Colon, "Expect ':' after map key.".as_bytes());
+            self.expression();
+            if count == MAX_ARGS {
+                self.error(
+                    ErrorCode::TooLarge,
+                    "Can't have more than 255 entries in a map literal.",
+                );
+            }
+            count += 1;
+
+            if !self.matches(TokenType::Comma) {
+                break;
+            }
+        }
+
+        self.consume(
+            TokenType::RightBrace,
+            "Expect '}' after map entries.".as_bytes(),
+        );
+        self.emit_bytes(OpCode::BuildMap as u8, count as u8);
+    }
+
+    fn index(&mut self) {
+        let can_assign = self.can_assign;
+        self.expression();
+        self.consume(
+            TokenType::RightBracket,
+            "Expect ']' after index.".as_bytes(),
+        );
+
+        if can_assign && self.matches(TokenType::Equal) {
+            self.expression();
+            self.emit_byte(OpCode::SetIndex as u8);
+        } else {
+            self.emit_byte(OpCode::GetIndex as u8);
+        }
+    }
+
+    fn dot(&mut self) {
+        self.consume(
+            TokenType::Identifier,
+            "Expect property name after '.'.".as_bytes(),
+        );
+        let previous = self.parser.previous.clone();
+        let name = self.identifier_constant(&previous);
+
+        if self.can_assign && self.matches(TokenType::Equal) {
+            self.expression();
+            self.emit_bytes(OpCode::SetProperty as u8, name);
+        } else {
+            self.emit_bytes(OpCode::GetProperty as u8, name);
         }
     }
 
@@ -304,7 +748,7 @@ impl<'a> Compiler<'a> {
             TokenType::False => self.emit_byte(OpCode::False as u8),
             TokenType::Nil => self.emit_byte(OpCode::Nil as u8),
             TokenType::True => self.emit_byte(OpCode::True as u8),
-            _ => return,
+            _ => (),
         }
     }
 
@@ -317,8 +761,20 @@ impl<'a> Compiler<'a> {
     }
 
     fn number(&mut self) {
-        let val = strtod_manual(&self.parser.previous.start).unwrap();
-        self.emit_constant(Value::Number(val));
+        if let Some(value) = self.number_value() {
+            self.emit_constant(Value::Number(value));
+        }
+    }
+
+    fn number_value(&mut self) -> Option<LNum> {
+        let token = &self.parser.previous;
+        match strtod_manual(&token.start[..token.length]) {
+            Ok(value) => Some(value),
+            Err(message) => {
+                self.error(ErrorCode::InvalidLiteral, message);
+                None
+            }
+        }
     }
 
     fn or(&mut self) {
@@ -332,37 +788,247 @@ impl<'a> Compiler<'a> {
         self.patch_jump(end_jump);
     }
 
+    // 'a ?: b' is 'a or b' with a lower precedence, it falls back on any falsey value.
+    fn elvis(&mut self) {
+        let else_jump = self.emit_jump(OpCode::JumpIfFalse as u8);
+        let end_jump = self.emit_jump(OpCode::Jump as u8);
+
+        self.patch_jump(else_jump);
+        self.emit_byte(OpCode::Pop as u8);
+
+        self.parse_precedence(Precedence::Coalesce + 1);
+        self.patch_jump(end_jump);
+    }
+
+    // 'a ?? b' only falls back when a is nil, so false and 0 are kept.
+    fn nil_coalesce(&mut self) {
+        self.emit_byte(OpCode::Dup as u8);
+        self.emit_byte(OpCode::Nil as u8);
+        self.emit_byte(OpCode::Equal as u8);
+        let keep_jump = self.emit_jump(OpCode::JumpIfFalse as u8);
+
+        // Pops the comparison and the nil value.
+        self.emit_byte(OpCode::Pop as u8);
+        self.emit_byte(OpCode::Pop as u8);
+        self.parse_precedence(Precedence::Coalesce + 1);
+        let end_jump = self.emit_jump(OpCode::Jump as u8);
+
+        self.patch_jump(keep_jump);
+        self.emit_byte(OpCode::Pop as u8);
+        self.patch_jump(end_jump);
+    }
+
+    // The else branch is parsed at the same precedence, so 'a ? b : c ? d : e' nests to the right.
+    fn conditional(&mut self) {
+        let else_jump = self.emit_jump(OpCode::JumpIfFalse as u8);
+        self.emit_byte(OpCode::Pop as u8);
+        self.expression();
+        self.consume(
+            TokenType::Colon,
+            "Expect ':' after then branch of conditional expression.".as_bytes(),
+        );
+        let end_jump = self.emit_jump(OpCode::Jump as u8);
+
+        self.patch_jump(else_jump);
+        self.emit_byte(OpCode::Pop as u8);
+        self.parse_precedence(Precedence::Conditional);
+        self.patch_jump(end_jump);
+    }
+
     fn string(&mut self) {
-        let bytes = &self.parser.previous.start[1..];
-        let length = self.parser.previous.length - 2;
-        let obj_str = ObjString::new(bytes, length);
-        self.strings.set(obj_str.hash, Value::Nil);
-        // Strings will have Nil as value, since a string will only be a string. Later on we'll have methods, variables etc
-        // that are stored as a string obj for the key and a real Value::{} as the value.
+        if self.parser.previous.start.starts_with(b"}") {
+            // The rest of an interpolated string, the expression before it was cut short.
+            self.error(ErrorCode::ExpectedExpression, "Expect expression.");
+            return;
+        }
+        self.string_segment();
+    }
+
+    // "a ${b} c" compiles to "a " + stringify(b) + " c".
+    fn interpolation(&mut self) {
+        self.string();
+        loop {
+            self.expression();
+            self.emit_byte(OpCode::Stringify as u8);
+            self.emit_byte(OpCode::Add as u8);
+
+            if !self.matches(TokenType::Interpolation) {
+                break;
+            }
+            self.string_segment();
+            self.emit_byte(OpCode::Add as u8);
+        }
+
+        if self.matches(TokenType::String) {
+            self.string_segment();
+            self.emit_byte(OpCode::Add as u8);
+        } else {
+            self.error_at_current(
+                ErrorCode::ExpectedToken,
+                "Expect '}' after interpolated expression.",
+            );
+        }
+    }
+
+    fn string_segment(&mut self) {
+        let token = self.parser.previous.clone();
+        if let Some(value) = self.string_value(&token) {
+            let obj = self.intern(&value);
+            self.emit_constant(Value::Object(obj));
+        }
+    }
 
-        self.emit_constant(Value::Object(Box::new(Obj::String(obj_str))));
+    // The decoded contents of a string token, or of one segment of an interpolated string.
+    // Returns None after reporting an invalid escape.
+    fn string_value(&mut self, token: &Token) -> Option<String> {
+        let text = &token.start[..token.length];
+        let raw = text.starts_with(b"r");
+        let quotes = if text[raw as usize..].starts_with(b"\"\"\"") {
+            3
+        } else {
+            1
+        };
+        // Segments start with the '}' of the previous expression instead of a quote,
+        // the ones followed by an expression end in '${'.
+        let prefix = raw as usize + quotes;
+        let suffix = if token.token_type == TokenType::Interpolation {
+            2
+        } else {
+            quotes
+        };
+        let contents = String::from_utf8_lossy(&text[prefix..text.len() - suffix]);
+
+        // Escapes are checked before the indentation is stripped, so errors point at the source.
+        if !raw {
+            if let Err(error) = unescape(&contents) {
+                self.error_in_token(token, prefix + error.offset, error.length, &error.message);
+                return None;
+            }
+        }
+        let contents = if quotes == 3 {
+            strip_indentation(&contents)
+        } else {
+            contents.into_owned()
+        };
+        if raw {
+            Some(contents)
+        } else {
+            unescape(&contents).ok()
+        }
     }
 
-    fn named_variable(&mut self, name: &Token) {
-        let mut arg = self.resolve_local(name);
+    // Returns the operand and the get and set instructions for the variable, and whether it's
+    // final. That is None for a global this script hasn't declared (yet), a previous one may have.
+    fn resolve_variable(&mut self, name: &Token) -> (u8, u8, u8, Option<bool>) {
+        let arg: u8;
         let get_op: u8;
         let set_op: u8;
+        let is_final: Option<bool>;
 
-        if arg != -1 {
+        if let Some(local) = self.resolve_local(name) {
+            arg = local;
             get_op = OpCode::GetLocal as u8;
             set_op = OpCode::SetLocal as u8;
+            is_final = Some(self.current.locals[local as usize].is_final);
+        } else if let Some(upvalue) = self.resolve_upvalue(name) {
+            arg = upvalue;
+            get_op = OpCode::GetUpvalue as u8;
+            set_op = OpCode::SetUpvalue as u8;
+            is_final = self
+                .current
+                .upvalues
+                .get(upvalue as usize)
+                .map(|u| u.is_final);
         } else {
-            arg = self.identifier_constant(name) as i8;
+            arg = self.identifier_constant(name);
             get_op = OpCode::GetGlobal as u8;
             set_op = OpCode::SetGlobal as u8;
+            is_final = self.globals.get(&token_text(name)).copied();
+        }
+
+        (arg, get_op, set_op, is_final)
+    }
+
+    fn check_assignment(&mut self, name: &Token<'a>, is_final: Option<bool>) {
+        match is_final {
+            Some(true) => self.final_assignment_error(name),
+            Some(false) => (),
+            None => self.global_assignments.push(name.clone()),
         }
+    }
+
+    // Compound assignments and increments read the variable, apply the operator and store the
+    // result, so the name is only resolved once.
+    fn named_variable(&mut self, name: &Token<'a>) {
+        let (arg, get_op, set_op, is_final) = self.resolve_variable(name);
 
         if self.can_assign && self.matches(TokenType::Equal) {
+            self.check_assignment(name, is_final);
             self.expression();
-            self.emit_bytes(set_op, arg as u8);
-        } else {
-            self.emit_bytes(get_op, arg as u8);
+            self.emit_bytes(set_op, arg);
+            return;
+        }
+
+        let compound = match self.parser.current.token_type {
+            TokenType::PlusEqual => Some(OpCode::Add),
+            TokenType::MinusEqual => Some(OpCode::Subtract),
+            TokenType::StarEqual => Some(OpCode::Multiply),
+            TokenType::SlashEqual => Some(OpCode::Divide),
+            TokenType::PercentEqual => Some(OpCode::Modulo),
+            _ => None,
+        };
+        if let Some(operator) = compound.filter(|_| self.can_assign) {
+            self.advance();
+            self.check_assignment(name, is_final);
+            self.emit_bytes(get_op, arg);
+            self.expression();
+            self.emit_byte(operator as u8);
+            self.emit_bytes(set_op, arg);
+            return;
+        }
+
+        // A postfix increment binds tighter than any operator, so it's allowed in any operand.
+        // The value from before the increment is left on the stack.
+        let increment = match self.parser.current.token_type {
+            TokenType::PlusPlus => Some(OpCode::Increment),
+            TokenType::MinusMinus => Some(OpCode::Decrement),
+            _ => None,
+        };
+        if let Some(operator) = increment {
+            self.advance();
+            self.check_assignment(name, is_final);
+            self.emit_bytes(get_op, arg);
+            self.emit_byte(OpCode::Dup as u8);
+            self.emit_byte(operator as u8);
+            self.emit_bytes(set_op, arg);
+            self.emit_byte(OpCode::Pop as u8);
+            return;
         }
+
+        self.emit_bytes(get_op, arg);
+    }
+
+    // Only reads the variable, for names like 'this' that can't be assigned to.
+    fn get_variable(&mut self, name: &Token) {
+        let (arg, get_op, _, _) = self.resolve_variable(name);
+        self.emit_bytes(get_op, arg);
+    }
+
+    fn prefix_increment(&mut self) {
+        let operator = match self.parser.previous.token_type {
+            TokenType::PlusPlus => OpCode::Increment,
+            _ => OpCode::Decrement,
+        };
+        self.consume(
+            TokenType::Identifier,
+            "Expect variable name after '++' or '--'.".as_bytes(),
+        );
+        let name = self.parser.previous.clone();
+        let (arg, get_op, set_op, is_final) = self.resolve_variable(&name);
+        self.check_assignment(&name, is_final);
+        self.emit_bytes(get_op, arg);
+        self.emit_byte(operator as u8);
+        self.emit_bytes(set_op, arg);
     }
 
     fn variable(&mut self) {
@@ -370,86 +1036,142 @@ impl<'a> Compiler<'a> {
         self.named_variable(&previous);
     }
 
+    fn super_(&mut self) {
+        if self.class_compilers.is_empty() {
+            self.error(
+                ErrorCode::OutsideClass,
+                "Can't use 'super' outside of a class.",
+            );
+            return;
+        }
+
+        self.consume(TokenType::Dot, "Expect '.' after 'super'.".as_bytes());
+        self.consume(
+            TokenType::Identifier,
+            "Expect superclass method name.".as_bytes(),
+        );
+        let previous = self.parser.previous.clone();
+        let name = self.identifier_constant(&previous);
+
+        self.get_variable(&synthetic_token("this"));
+        self.get_variable(&synthetic_token("super"));
+        self.emit_bytes(OpCode::GetSuper as u8, name);
+    }
+
+    fn this(&mut self) {
+        if self.class_compilers.is_empty() {
+            self.error(
+                ErrorCode::OutsideClass,
+                "Can't use 'this' outside of a class.",
+            );
+            return;
+        }
+
+        // 'this' can never be assigned to.
+        let previous = self.parser.previous.clone();
+        self.get_variable(&previous);
+    }
+
     fn unary(&mut self) {
         let operator_type = self.parser.previous.token_type.clone();
 
-        // compile the operand.
-        self.parse_precedence(Precedence::Assignment);
+        // compile the operand, unary binds tighter than any binary operator.
+        self.parse_precedence(Precedence::Unary);
 
         // emit the operator instruction.
         match operator_type {
             TokenType::Bang => self.emit_byte(OpCode::Not as u8),
             TokenType::Minus => self.emit_byte(OpCode::Negate as u8),
-            _ => return,
+            TokenType::Tilde => self.emit_byte(OpCode::BitNot as u8),
+            _ => (),
         }
     }
 
     fn parse_precedence(&mut self, precedence: Precedence) {
+        // A token that can't start an expression is left alone,
+        // it may be the '}' or keyword the parser recovers at.
+        let Some(prefix) = self.get_rule(self.parser.current.token_type.clone()).prefix else {
+            self.error_at_current(ErrorCode::ExpectedExpression, "Expect expression.");
+            return;
+        };
         self.advance();
 
-        if let Some(prefix) = self
-            .get_rule(self.parser.previous.token_type.clone())
-            .prefix
-        {
-            self.can_assign = precedence <= Precedence::Assignment;
-            prefix(self);
-
-            while precedence
-                <= self
-                    .get_rule(self.parser.current.token_type.clone())
-                    .precedence
-            {
-                self.advance();
-                if let Some(infix) = self.get_rule(self.parser.previous.token_type.clone()).infix {
-                    infix(self);
-                }
+        // Nested expressions overwrite can_assign, so it's restored before every infix rule.
+        let can_assign = precedence <= Precedence::Assignment;
+        self.can_assign = can_assign;
+        prefix(self);
 
-                if self.can_assign && self.matches(TokenType::Equal) {
-                    self.error("Invalid assignment target.".as_bytes());
-                }
+        while precedence
+            <= self
+                .get_rule(self.parser.current.token_type.clone())
+                .precedence
+        {
+            self.advance();
+            if let Some(infix) = self.get_rule(self.parser.previous.token_type.clone()).infix {
+                self.can_assign = can_assign;
+                infix(self);
             }
-        } else {
-            self.error("Expect expression.".as_bytes());
         }
-    }
-
-    fn identifier_constant(&mut self, name: &Token) -> u8 {
-        self.make_constant(Value::Object(Box::new(Obj::String(ObjString::new(
-            name.start,
-            name.length,
-        )))))
-    }
 
-    fn identifiers_equal(&self, a: &Token, b: &Token) -> bool {
-        if a.length != b.length {
-            return false;
+        if can_assign && self.matches(TokenType::Equal) {
+            self.error_with_help(
+                ErrorCode::InvalidAssignmentTarget,
+                "Invalid assignment target.",
+                "only variables, properties and index expressions can be assigned to.",
+            );
+        } else if can_assign && self.is_compound_assignment() {
+            self.advance();
+            self.error_with_help(
+                ErrorCode::InvalidAssignmentTarget,
+                "Invalid assignment target.",
+                "compound assignments, '++' and '--' only work on variables.",
+            );
         }
+    }
 
-        if &a.start[..a.length] != &b.start[..b.length] {
-            return false;
-        }
+    fn is_compound_assignment(&self) -> bool {
+        matches!(
+            self.parser.current.token_type,
+            TokenType::PlusEqual
+                | TokenType::MinusEqual
+                | TokenType::StarEqual
+                | TokenType::SlashEqual
+                | TokenType::PercentEqual
+                | TokenType::PlusPlus
+                | TokenType::MinusMinus
+        )
+    }
 
-        true
+    fn identifier_constant(&mut self, name: &Token) -> u8 {
+        let obj = self.intern(&String::from_utf8_lossy(&name.start[..name.length]));
+        self.make_constant(Value::Object(obj))
     }
 
-    fn resolve_local(&mut self, previous: &Token) -> i8 {
-        for i in 0..self.current.local_count {
-            let local = self.current.locals.get(i).unwrap();
+    fn resolve_local(&mut self, name: &Token) -> Option<u8> {
+        let local = self.current.resolve_local(name)?;
+        if self.current.locals[local].depth == -1 {
+            self.error_with_help(
+                ErrorCode::OwnInitializer,
+                "Can't read local variable in its own initializer.",
+                "declare the variable before using it, or give it a different name.",
+            );
+        }
+        Some(local as u8)
+    }
 
-            if self.identifiers_equal(&previous, &local.name) {
-                if local.depth == -1 {
-                    self.error("Can't reqad local variable in its own initializer.".as_bytes());
-                }
-                return i as i8;
+    fn resolve_upvalue(&mut self, name: &Token) -> Option<u8> {
+        match self.current.resolve_upvalue(name) {
+            Ok(upvalue) => upvalue,
+            Err(message) => {
+                self.error(ErrorCode::TooLarge, message);
+                Some(0)
             }
         }
-
-        return -1;
     }
 
     fn add_local(&mut self, name: Token<'a>) {
         if self.current.local_count == MAX_LOCALS as usize {
-            self.error("Too many local variables in function.".as_bytes());
+            self.error(ErrorCode::TooLarge, "Too many local variables in function.");
         }
 
         let local = Local::new(name, -1);
@@ -458,20 +1180,32 @@ impl<'a> Compiler<'a> {
     }
 
     fn declare_variable(&mut self) {
+        let previous = self.parser.previous.clone();
+
         if self.current.scope_depth == 0 {
+            let name = token_text(&previous);
+            if self.globals.get(&name) == Some(&true) {
+                self.error_with_help(
+                    ErrorCode::AssignToFinal,
+                    &format!("Can't redeclare final variable '{}'.", name),
+                    "it was declared with 'let final'.",
+                );
+            }
+            self.globals.insert(name, false);
             return;
         }
 
-        let previous = self.parser.previous.clone();
-
-        for i in 0..self.current.local_count {
+        for i in (0..self.current.local_count).rev() {
             let local = self.current.locals.get(i).unwrap();
             if local.depth != -1 && local.depth < self.current.scope_depth as i8 {
                 break;
             }
 
-            if self.identifiers_equal(&previous, &local.name) {
-                self.error("Already a variable with this name in this scope.".as_bytes());
+            if identifiers_equal(&previous, &local.name) {
+                self.error(
+                    ErrorCode::DuplicateVariable,
+                    "Already a variable with this name in this scope.",
+                );
             }
         }
 
@@ -492,7 +1226,20 @@ impl<'a> Compiler<'a> {
         self.identifier_constant(&previous)
     }
 
+    // Called right after the variable is declared, before its initializer is compiled.
+    fn mark_final(&mut self) {
+        if self.current.scope_depth == 0 {
+            let name = token_text(&self.parser.previous);
+            self.globals.insert(name, true);
+            return;
+        }
+        self.current.locals[self.current.local_count - 1].is_final = true;
+    }
+
     fn mark_initialized(&mut self) {
+        if self.current.scope_depth == 0 {
+            return;
+        }
         self.current.locals[self.current.local_count - 1].depth = self.current.scope_depth as i8;
     }
 
@@ -505,6 +1252,29 @@ impl<'a> Compiler<'a> {
         self.emit_bytes(OpCode::DefineGlobal as u8, global);
     }
 
+    fn argument_list(&mut self) -> u8 {
+        let mut arg_count = 0;
+        if !self.check(TokenType::RightParen) {
+            loop {
+                self.expression();
+                if arg_count == MAX_ARGS {
+                    self.error(ErrorCode::TooLarge, "Can't have more than 255 arguments.");
+                }
+                arg_count += 1;
+
+                if !self.matches(TokenType::Comma) {
+                    break;
+                }
+            }
+        }
+
+        self.consume(
+            TokenType::RightParen,
+            "Expect ')' after arguments.".as_bytes(),
+        );
+        arg_count as u8
+    }
+
     fn and(&mut self) {
         let end_jump = self.emit_jump(OpCode::JumpIfFalse as u8);
 
@@ -514,9 +1284,8 @@ impl<'a> Compiler<'a> {
         self.patch_jump(end_jump);
     }
 
-    fn get_rule(&mut self, token_type: TokenType) -> ParseRule<'a> {
-        let rules = self.rules();
-        *rules.get(&token_type).unwrap()
+    fn get_rule(&self, token_type: TokenType) -> &'static ParseRule {
+        &RULES[token_type as usize]
     }
 
     fn expression(&mut self) {
@@ -531,10 +1300,151 @@ impl<'a> Compiler<'a> {
         self.consume(TokenType::RightBrace, "Expect '}' after block.".as_bytes());
     }
 
+    fn function(&mut self, function_type: FunctionType) {
+        let name = self.parser.previous.clone();
+        let enclosing = std::mem::replace(&mut self.current, TinyCompiler::new(function_type));
+        self.current.enclosing = Some(Box::new(enclosing));
+        self.current.function.name = Some(ObjString::new(&String::from_utf8_lossy(
+            &name.start[..name.length],
+        )));
+
+        self.begin_scope();
+
+        self.consume(
+            TokenType::LeftParen,
+            "Expect '(' after function name.".as_bytes(),
+        );
+        if !self.check(TokenType::RightParen) {
+            loop {
+                self.current.function.arity += 1;
+                if self.current.function.arity > MAX_ARGS {
+                    self.error_at_current(
+                        ErrorCode::TooLarge,
+                        "Can't have more than 255 parameters.",
+                    );
+                }
+                let constant = self.parse_variable("Expect parameter name.".as_bytes());
+                self.define_variable(constant);
+
+                if !self.matches(TokenType::Comma) {
+                    break;
+                }
+            }
+        }
+        self.consume(
+            TokenType::RightParen,
+            "Expect ')' after parameters.".as_bytes(),
+        );
+        self.consume(
+            TokenType::LeftBrace,
+            "Expect '{' before function body.".as_bytes(),
+        );
+        self.block();
+
+        // No end_scope needed, the whole frame is discarded when the function returns.
+        let (function, upvalues) = self.end_compiler();
+        let obj = self.alloc(Obj::Function(function));
+        let constant = self.make_constant(Value::Object(obj));
+        self.emit_bytes(OpCode::Closure as u8, constant);
+
+        for upvalue in upvalues {
+            self.emit_byte(upvalue.is_local as u8);
+            self.emit_byte(upvalue.index);
+        }
+    }
+
+    fn method(&mut self) {
+        // Skip the token instead of compiling a method body around it,
+        // the class body keeps going until its closing '}'.
+        if !self.check(TokenType::Identifier) {
+            self.error_at_current(ErrorCode::ExpectedToken, "Expect method name.");
+            self.advance();
+            return;
+        }
+        self.advance();
+        let previous = self.parser.previous.clone();
+        let constant = self.identifier_constant(&previous);
+
+        let function_type = if &previous.start[..previous.length] == b"init" {
+            FunctionType::Initializer
+        } else {
+            FunctionType::Method
+        };
+        self.function(function_type);
+        self.emit_bytes(OpCode::Method as u8, constant);
+    }
+
+    fn class_declaration(&mut self) {
+        self.consume(TokenType::Identifier, "Expect class name.".as_bytes());
+        let class_name = self.parser.previous.clone();
+        let name_constant = self.identifier_constant(&class_name);
+        self.declare_variable();
+
+        self.emit_bytes(OpCode::Class as u8, name_constant);
+        self.define_variable(name_constant);
+
+        // Every class gets a 'super' local holding its superclass. It stays nil when the class
+        // doesn't inherit, so using 'super' there is reported when it runs.
+        self.begin_scope();
+        let has_superclass = self.matches(TokenType::Less);
+        if has_superclass {
+            self.consume(TokenType::Identifier, "Expect superclass name.".as_bytes());
+            self.can_assign = false;
+            self.variable();
+
+            if identifiers_equal(&class_name, &self.parser.previous) {
+                self.error(
+                    ErrorCode::InheritFromSelf,
+                    "A class can't inherit from itself.",
+                );
+            }
+        } else {
+            self.emit_byte(OpCode::Nil as u8);
+        }
+        self.add_local(synthetic_token("super"));
+        self.define_variable(0);
+
+        if has_superclass {
+            self.named_variable(&class_name);
+            self.emit_byte(OpCode::Inherit as u8);
+        }
+
+        self.class_compilers.push(ClassCompiler {});
+
+        // Load the class back onto the stack so the methods can be bound to it.
+        self.can_assign = false;
+        self.named_variable(&class_name);
+        self.consume(
+            TokenType::LeftBrace,
+            "Expect '{' before class body.".as_bytes(),
+        );
+        while !self.check(TokenType::RightBrace) && !self.check(TokenType::Eof) {
+            self.method();
+        }
+        self.consume(
+            TokenType::RightBrace,
+            "Expect '}' after class body.".as_bytes(),
+        );
+        self.emit_byte(OpCode::Pop as u8);
+        self.end_scope();
+
+        self.class_compilers.pop();
+    }
+
+    fn fun_declaration(&mut self) {
+        let global = self.parse_variable("Expect function name.".as_bytes());
+        // A function may refer to itself in its body, so it's initialized right away.
+        self.mark_initialized();
+        self.function(FunctionType::Function);
+        self.define_variable(global);
+    }
+
     fn var_declaration(&mut self) {
-        self.current.is_final = self.matches(TokenType::Final);
-        // FIXME: emit final opcode here
+        let is_final = self.matches(TokenType::Final);
         let global: u8 = self.parse_variable("Expect variable name.".as_bytes());
+        if is_final {
+            self.mark_final();
+        }
 
         if self.matches(TokenType::Equal) {
             self.expression();
@@ -547,7 +1457,12 @@ impl<'a> Compiler<'a> {
             "Expect ';' after variable declaration.".as_bytes(),
         );
 
-        self.define_variable(global);
+        // Final globals are also checked when they run, for scripts entered later in the REPL.
+        if is_final && self.current.scope_depth == 0 {
+            self.emit_bytes(OpCode::DefineFinalGlobal as u8, global);
+        } else {
+            self.define_variable(global);
+        }
     }
 
     fn expression_statement(&mut self) {
@@ -559,7 +1474,7 @@ impl<'a> Compiler<'a> {
         self.emit_byte(OpCode::Pop as u8);
     }
 
-    fn for_statement(&mut self) {
+    fn for_statement(&mut self, label: Option<Token<'a>>) {
         self.begin_scope();
         self.consume(TokenType::LeftParen, "Expect '(' after 'for'.".as_bytes());
         if self.matches(TokenType::Semicolon) {
@@ -598,6 +1513,7 @@ impl<'a> Compiler<'a> {
             self.patch_jump(body_jump);
         }
 
+        self.begin_loop(label, loop_start);
         self.statement();
         self.emit_loop(loop_start);
 
@@ -605,6 +1521,7 @@ impl<'a> Compiler<'a> {
             self.patch_jump(exit_jump);
             self.emit_byte(OpCode::Pop as u8);
         }
+        self.end_loop();
 
         self.end_scope();
     }
@@ -632,13 +1549,42 @@ impl<'a> Compiler<'a> {
         self.patch_jump(else_jump);
     }
 
+    fn return_statement(&mut self) {
+        if self.current.function_type == FunctionType::Script {
+            self.error_with_help(
+                ErrorCode::InvalidReturn,
+                "Can't return from top-level code.",
+                "'return' can only be used inside a function.",
+            );
+        }
+
+        if self.matches(TokenType::Semicolon) {
+            self.emit_return();
+        } else {
+            if self.current.function_type == FunctionType::Initializer {
+                self.error_with_help(
+                    ErrorCode::InvalidReturn,
+                    "Can't return a value from an initializer.",
+                    "an initializer always returns 'this', use a bare 'return;' instead.",
+                );
+            }
+
+            self.expression();
+            self.consume(
+                TokenType::Semicolon,
+                "Expect ';' after return value.".as_bytes(),
+            );
+            self.emit_byte(OpCode::Return as u8);
+        }
+    }
+
     fn print_statement(&mut self) {
         self.expression();
         self.consume(TokenType::Semicolon, "Expect ';' after value.".as_bytes());
         self.emit_byte(OpCode::Print as u8);
     }
 
-    fn while_statement(&mut self) {
+    fn while_statement(&mut self, label: Option<Token<'a>>) {
         let loop_start = self.current_chunk().code.len();
         self.consume(TokenType::LeftParen, "Expect '(' after 'while'.".as_bytes());
         self.expression();
@@ -649,40 +1595,452 @@ impl<'a> Compiler<'a> {
 
         let exit_jump = self.emit_jump(OpCode::JumpIfFalse as u8);
         self.emit_byte(OpCode::Pop as u8);
+        self.begin_loop(label, loop_start);
         self.statement();
         self.emit_loop(loop_start);
 
         self.patch_jump(exit_jump);
         self.emit_byte(OpCode::Pop as u8);
+        self.end_loop();
+    }
+
+    // match (value) { 1, 2 => ..., 3..10 if ok => ..., "a" => ..., _ => ... }
+    //
+    // The arms come first and the code picking one of them after, so it's only generated once
+    // every pattern is known. When all patterns are dense integers it's a single jump table
+    // instead of a comparison for every pattern.
+    fn match_statement(&mut self) {
+        let keyword = self.parser.previous.clone();
+        self.begin_scope();
+        self.consume(TokenType::LeftParen, "Expect '(' after 'match'.".as_bytes());
+        self.expression();
+        self.consume(
+            TokenType::RightParen,
+            "Expect ')' after match value.".as_bytes(),
+        );
+        // Kept in a local nobody can name, so every pattern can read it.
+        self.add_local(synthetic_token("match value"));
+        self.mark_initialized();
+        let value_slot = (self.current.local_count - 1) as u8;
+        self.consume(
+            TokenType::LeftBrace,
+            "Expect '{' before match arms.".as_bytes(),
+        );
+
+        let dispatch_jump = self.emit_jump(OpCode::Jump as u8);
+        let mut arms = Vec::new();
+        let mut default_start = None;
+        let mut end_jumps = Vec::new();
+        while !self.check(TokenType::RightBrace) && !self.check(TokenType::Eof) {
+            let arm_start = self.parser.current.span;
+            if default_start.is_some() {
+                self.error_at_current(
+                    ErrorCode::InvalidMatch,
+                    "Arms after the default arm are never reached.",
+                );
+            }
+
+            if self.check_default_pattern() {
+                self.advance();
+                self.consume(TokenType::FatArrow, "Expect '=>' after '_'.".as_bytes());
+                default_start = Some(self.current_chunk().code.len());
+                self.statement();
+                end_jumps.push(self.emit_jump(OpCode::Jump as u8));
+            } else if let Some(arm) = self.match_arm(&mut end_jumps) {
+                arms.push(arm);
+            }
+            self.matches(TokenType::Comma);
+
+            if self.parser.panic_mode {
+                self.synchronize();
+                if self.parser.current.span == arm_start {
+                    self.advance();
+                }
+            }
+        }
+        self.consume(
+            TokenType::RightBrace,
+            "Expect '}' after match arms.".as_bytes(),
+        );
+
+        self.patch_jump(dispatch_jump);
+        match default_start {
+            Some(default_start) => {
+                if !self.jump_table(&arms, value_slot, default_start) {
+                    self.match_dispatch(&arms, value_slot, default_start);
+                }
+            }
+            None => {
+                let diagnostic = Self::diagnostic_at(
+                    &keyword,
+                    ErrorCode::InvalidMatch,
+                    "Match has no default arm.",
+                );
+                self.error_at(diagnostic.with_note("add a '_ => ...' arm for every other value."));
+            }
+        }
+        for jump in end_jumps {
+            self.patch_jump(jump);
+        }
+        self.end_scope();
+    }
+
+    fn check_default_pattern(&self) -> bool {
+        let current = &self.parser.current;
+        current.token_type == TokenType::Identifier && &current.start[..current.length] == b"_"
+    }
+
+    // Compiles the guard and body of an arm, its patterns are tested by the dispatch.
+    fn match_arm(&mut self, end_jumps: &mut Vec<usize>) -> Option<MatchArm> {
+        let mut patterns = vec![self.pattern()?];
+        while self.matches(TokenType::Comma) {
+            patterns.push(self.pattern()?);
+        }
+
+        let start = self.current_chunk().code.len();
+        let guard_jump = if self.matches(TokenType::If) {
+            self.expression();
+            let jump = self.emit_jump(OpCode::JumpIfFalse as u8);
+            self.emit_byte(OpCode::Pop as u8);
+            Some(jump)
+        } else {
+            None
+        };
+        self.consume(
+            TokenType::FatArrow,
+            "Expect '=>' after match pattern.".as_bytes(),
+        );
+        self.statement();
+        end_jumps.push(self.emit_jump(OpCode::Jump as u8));
+
+        let guard_failed = guard_jump.map(|jump| {
+            self.patch_jump(jump);
+            self.emit_byte(OpCode::Pop as u8);
+            self.emit_jump(OpCode::Jump as u8)
+        });
+        Some(MatchArm {
+            patterns,
+            start,
+            guard_failed,
+        })
     }
 
-    // FIXME: doesn't seem to sync up properly.
+    // A literal, or a range of numbers like 1..5 without or 1..=5 with its upper bound.
+    fn pattern(&mut self) -> Option<Pattern> {
+        let lower = self.pattern_literal()?;
+        let inclusive = if self.matches(TokenType::DotDotEqual) {
+            true
+        } else if self.matches(TokenType::DotDot) {
+            false
+        } else {
+            return Some(Pattern::Literal(self.make_constant(lower)));
+        };
+
+        let upper = self.pattern_literal()?;
+        if !matches!((&lower, &upper), (Value::Number(_), Value::Number(_))) {
+            self.error(ErrorCode::InvalidMatch, "Range patterns must be numbers.");
+            return None;
+        }
+        Some(Pattern::Range {
+            lower: self.make_constant(lower),
+            upper: self.make_constant(upper),
+            inclusive,
+        })
+    }
+
+    fn pattern_literal(&mut self) -> Option<Value> {
+        let negative = self.matches(TokenType::Minus);
+        if self.matches(TokenType::Number) {
+            let value = self.number_value()?;
+            return Some(Value::Number(if negative { value.negate() } else { value }));
+        }
+
+        let literal = match self.parser.current.token_type {
+            _ if negative => None,
+            TokenType::True => Some(Value::Bool(true)),
+            TokenType::False => Some(Value::Bool(false)),
+            TokenType::Nil => Some(Value::Nil),
+            TokenType::String if !self.parser.current.start.starts_with(b"}") => {
+                self.advance();
+                let token = self.parser.previous.clone();
+                let value = self.string_value(&token)?;
+                // Becomes a constant right away, so it can't be collected before that.
+                return Some(Value::Object(self.intern(&value)));
+            }
+            _ => None,
+        };
+        if literal.is_none() {
+            let diagnostic = Self::diagnostic_at(
+                &self.parser.current,
+                ErrorCode::ExpectedExpression,
+                "Expect a pattern.",
+            );
+            self.error_at(diagnostic.with_note(
+                "patterns are numbers, strings, true, false, nil, ranges like 1..5 or '_'.",
+            ));
+            return None;
+        }
+        self.advance();
+        literal
+    }
+
+    // Tests the patterns of every arm in order and jumps back to the first one that matches.
+    fn match_dispatch(&mut self, arms: &[MatchArm], value_slot: u8, default_start: usize) {
+        for arm in arms {
+            for pattern in &arm.patterns {
+                self.emit_bytes(OpCode::GetLocal as u8, value_slot);
+                match *pattern {
+                    Pattern::Literal(constant) => {
+                        self.emit_bytes(OpCode::Constant as u8, constant);
+                        self.emit_byte(OpCode::Equal as u8);
+                    }
+                    Pattern::Range {
+                        lower,
+                        upper,
+                        inclusive,
+                    } => {
+                        self.emit_bytes(OpCode::Constant as u8, lower);
+                        self.emit_bytes(OpCode::Constant as u8, upper);
+                        self.emit_bytes(OpCode::InRange as u8, inclusive as u8);
+                    }
+                }
+                let next_pattern = self.emit_jump(OpCode::JumpIfFalse as u8);
+                self.emit_byte(OpCode::Pop as u8);
+                self.emit_loop(arm.start);
+                self.patch_jump(next_pattern);
+                self.emit_byte(OpCode::Pop as u8);
+            }
+            if let Some(guard_failed) = arm.guard_failed {
+                self.patch_jump(guard_failed);
+            }
+        }
+        self.emit_loop(default_start);
+    }
+
+    // Used when every pattern is an integer literal, there are no guards and the integers
+    // fill at least half of the range they span. Returns false when the match doesn't qualify.
+    fn jump_table(&mut self, arms: &[MatchArm], value_slot: u8, default_start: usize) -> bool {
+        let mut cases: Vec<(i64, usize)> = Vec::new();
+        for arm in arms {
+            if arm.guard_failed.is_some() {
+                return false;
+            }
+            for pattern in &arm.patterns {
+                let Pattern::Literal(constant) = *pattern else {
+                    return false;
+                };
+                match self.current_chunk().constants.values[constant as usize].as_int() {
+                    // Earlier arms win, like they do when comparing one by one.
+                    Some(value) if !cases.iter().any(|(case, _)| *case == value) => {
+                        cases.push((value, arm.start))
+                    }
+                    Some(_) => {}
+                    None => return false,
+                }
+            }
+        }
+
+        let (Some(lowest), Some(highest)) = (
+            cases.iter().map(|(value, _)| *value).min(),
+            cases.iter().map(|(value, _)| *value).max(),
+        ) else {
+            return false;
+        };
+        let Some(span) = highest
+            .checked_sub(lowest)
+            .and_then(|span| u8::try_from(span + 1).ok())
+        else {
+            return false;
+        };
+        if cases.len() < 3 || cases.len() * 2 < span as usize {
+            return false;
+        }
+
+        // The values in between without an arm of their own go to the default arm.
+        let mut targets = vec![default_start; span as usize + 1];
+        for (value, start) in &cases {
+            targets[(value - lowest) as usize] = *start;
+        }
+
+        let lowest = self.make_constant(Value::Number(LNum::Int(LInt::new(lowest))));
+        self.emit_bytes(OpCode::GetLocal as u8, value_slot);
+        self.emit_bytes(OpCode::JumpTable as u8, lowest);
+        self.emit_byte(span);
+
+        // The arms are all before the table, so the offsets count backwards.
+        let end = self.current_chunk().code.len() + targets.len() * 2;
+        for target in targets {
+            let offset = end - target;
+            if offset > u16::MAX as usize {
+                self.error(ErrorCode::TooLarge, "Too much code to jump over.");
+            }
+            self.emit_bytes(((offset >> 8) & 0xff) as u8, (offset & 0xff) as u8);
+        }
+        true
+    }
+
+    // A label names a loop, so 'break outer;' can leave more than the innermost one.
+    fn labeled_statement(&mut self) {
+        self.advance();
+        let label = self.parser.previous.clone();
+        self.consume(TokenType::Colon, "Expect ':' after label.".as_bytes());
+
+        if self.matches(TokenType::While) {
+            self.while_statement(Some(label));
+        } else if self.matches(TokenType::For) {
+            self.for_statement(Some(label));
+        } else {
+            self.error_at_current(ErrorCode::ExpectedToken, "Expect a loop after a label.");
+        }
+    }
+
+    fn break_statement(&mut self) {
+        let Some(target) = self.loop_target("break") else {
+            return;
+        };
+        self.pop_loop_locals(target);
+        let jump = self.emit_jump(OpCode::Jump as u8);
+        self.current.loops[target].break_jumps.push(jump);
+    }
+
+    fn continue_statement(&mut self) {
+        let Some(target) = self.loop_target("continue") else {
+            return;
+        };
+        self.pop_loop_locals(target);
+        let start = self.current.loops[target].start;
+        self.emit_loop(start);
+    }
+
+    // Parses the rest of a 'break' or 'continue' and finds the loop it applies to,
+    // the innermost one unless a label is given.
+    fn loop_target(&mut self, keyword: &str) -> Option<usize> {
+        let keyword_token = self.parser.previous.clone();
+        let label = if self.matches(TokenType::Identifier) {
+            Some(self.parser.previous.clone())
+        } else {
+            None
+        };
+        self.consume(
+            TokenType::Semicolon,
+            format!("Expect ';' after '{}'.", keyword).as_bytes(),
+        );
+
+        let loops = &self.current.loops;
+        let target = match &label {
+            Some(label) => loops.iter().rposition(|context| {
+                context
+                    .label
+                    .as_ref()
+                    .is_some_and(|name| identifiers_equal(name, label))
+            }),
+            None => loops.len().checked_sub(1),
+        };
+        if target.is_some() {
+            return target;
+        }
+
+        let diagnostic = match label {
+            Some(label) if !loops.is_empty() => {
+                let name = String::from_utf8_lossy(&label.start[..label.length]);
+                Self::diagnostic_at(
+                    &label,
+                    ErrorCode::UnknownLabel,
+                    format!("No loop labeled '{}'.", name).as_str(),
+                )
+            }
+            _ => Self::diagnostic_at(
+                &keyword_token,
+                ErrorCode::OutsideLoop,
+                format!("Can't use '{}' outside of a loop.", keyword).as_str(),
+            ),
+        };
+        self.error_at(diagnostic);
+        None
+    }
+
+    fn begin_loop(&mut self, label: Option<Token<'a>>, start: usize) {
+        self.current.loops.push(LoopContext {
+            label,
+            start,
+            scope_depth: self.current.scope_depth,
+            break_jumps: Vec::new(),
+        });
+    }
+
+    // Points the breaks of the innermost loop at the code right after it.
+    fn end_loop(&mut self) {
+        if let Some(context) = self.current.loops.pop() {
+            for jump in context.break_jumps {
+                self.patch_jump(jump);
+            }
+        }
+    }
+
+    // Pops the locals of the scopes a jump out of the loop body leaves. Unlike end_scope the
+    // compiler keeps them, the code after the jump is still inside those scopes.
+    fn pop_loop_locals(&mut self, target: usize) {
+        let depth = self.current.loops[target].scope_depth as i8;
+        for i in (0..self.current.local_count).rev() {
+            if self.current.locals[i].depth <= depth {
+                break;
+            }
+            if self.current.locals[i].is_captured {
+                self.emit_byte(OpCode::CloseUpvalue as u8);
+            } else {
+                self.emit_byte(OpCode::Pop as u8);
+            }
+        }
+    }
+
+    // Skips tokens until a statement boundary, so one error doesn't cascade into a pile of others
+    // and the errors after it still get reported.
     fn synchronize(&mut self) {
         self.parser.panic_mode = false;
 
         while self.parser.current.token_type != TokenType::Eof {
-            if self.parser.previous.token_type == TokenType::Semicolon {
+            // Tokens that can't start a statement, like a stray ')', are skipped as well.
+            // Stopping at them would report them again, or never move past them at all.
+            if self.parser.previous.token_type == TokenType::Semicolon && self.can_start_statement()
+            {
                 return;
             }
             match self.parser.current.token_type {
-                TokenType::Class => {}
-                TokenType::Fun => {}
-                TokenType::Let => {}
-                TokenType::For => {}
-                TokenType::If => {}
-                TokenType::While => {}
-                TokenType::Print => {}
-                TokenType::Return => {}
-
-                _ => return,
+                TokenType::Class
+                | TokenType::Fun
+                | TokenType::Let
+                | TokenType::For
+                | TokenType::If
+                | TokenType::While
+                | TokenType::Print
+                | TokenType::Return
+                | TokenType::Match
+                | TokenType::Break
+                | TokenType::Continue => return,
+                // A stray '}' at the top level has no block to close, so it's skipped.
+                TokenType::RightBrace if self.current.scope_depth > 0 => return,
+                _ => {}
             }
 
             self.advance();
         }
     }
 
+    fn can_start_statement(&self) -> bool {
+        let token_type = self.parser.current.token_type.clone();
+        match token_type {
+            TokenType::LeftBrace => true,
+            TokenType::RightBrace => self.current.scope_depth > 0,
+            _ => self.get_rule(token_type).prefix.is_some(),
+        }
+    }
+
     fn declaration(&mut self) {
-        if self.matches(TokenType::Let) {
+        if self.matches(TokenType::Class) {
+            self.class_declaration();
+        } else if self.matches(TokenType::Fun) {
+            self.fun_declaration();
+        } else if self.matches(TokenType::Let) {
             self.var_declaration();
         } else {
             self.statement();
@@ -697,11 +2055,23 @@ impl<'a> Compiler<'a> {
         if self.matches(TokenType::Print) {
             self.print_statement();
         } else if self.matches(TokenType::For) {
-            self.for_statement();
+            self.for_statement(None);
         } else if self.matches(TokenType::If) {
             self.if_statement();
+        } else if self.matches(TokenType::Return) {
+            self.return_statement();
         } else if self.matches(TokenType::While) {
-            self.while_statement();
+            self.while_statement(None);
+        } else if self.matches(TokenType::Match) {
+            self.match_statement();
+        } else if self.matches(TokenType::Break) {
+            self.break_statement();
+        } else if self.matches(TokenType::Continue) {
+            self.continue_statement();
+        } else if self.check(TokenType::Identifier)
+            && self.scanner.peek_token_type() == TokenType::Colon
+        {
+            self.labeled_statement();
         } else if self.matches(TokenType::LeftBrace) {
             self.begin_scope();
             self.block();
@@ -711,368 +2081,60 @@ impl<'a> Compiler<'a> {
         }
     }
 
-    fn error_at_current(&mut self, message: &[u8]) {
-        self.error_at(&self.parser.current.clone(), message);
+    fn error_at_current(&mut self, code: ErrorCode, message: &str) {
+        let diagnostic = Self::diagnostic_at(&self.parser.current, code, message);
+        self.error_at(diagnostic);
     }
 
-    fn error(&mut self, message: &[u8]) {
-        self.error_at(&self.parser.previous.clone(), message);
+    fn error(&mut self, code: ErrorCode, message: &str) {
+        let diagnostic = Self::diagnostic_at(&self.parser.previous, code, message);
+        self.error_at(diagnostic);
     }
 
-    fn error_at(&mut self, token: &Token, message: &[u8]) {
-        if self.parser.panic_mode {
-            return;
-        }
-        self.parser.panic_mode = true;
-        eprint!("[line {}] Error", token.line);
-
-        match token.token_type {
-            TokenType::Eof => {
-                eprint!(" at end");
-            }
-            TokenType::Error => {
-                // Do nothing
-            }
-            _ => {
-                eprint!(
-                    " at '{:?}'",
-                    std::str::from_utf8(&token.start[0..token.length]).expect("Invalid UTF-8.")
-                );
-            }
-        }
+    fn error_with_help(&mut self, code: ErrorCode, message: &str, help: &str) {
+        let diagnostic = Self::diagnostic_at(&self.parser.previous, code, message);
+        self.error_at(diagnostic.with_note(help));
+    }
 
-        eprintln!(
-            ": {}",
-            std::str::from_utf8(message).expect("Invalid UTF-8.")
+    // Points at part of a token, like an invalid escape inside a string that spans multiple lines.
+    fn error_in_token(&mut self, token: &Token, offset: usize, length: usize, message: &str) {
+        let before = &token.start[..offset];
+        let newlines = before.iter().filter(|c| **c == b'\n').count();
+        let column = match before.iter().rposition(|c| *c == b'\n') {
+            Some(newline) => String::from_utf8_lossy(&before[newline..]).chars().count(),
+            None => token.column + String::from_utf8_lossy(before).chars().count(),
+        };
+        let span = Span {
+            start: token.span.start + offset,
+            end: token.span.start + offset + length,
+        };
+        let diagnostic = Diagnostic::new(
+            ErrorCode::InvalidLiteral,
+            message,
+            span,
+            token.line + newlines,
+            column,
         );
-        self.parser.had_error = true;
+        self.error_at(diagnostic);
     }
 
-    fn rules(&self) -> HashMap<TokenType, ParseRule<'a>> {
-        let mut rules = HashMap::new();
-
-        rules.insert(
-            TokenType::LeftParen,
-            ParseRule {
-                prefix: Some(Self::grouping),
-                infix: None,
-                precedence: Precedence::None,
-            },
-        );
-        rules.insert(
-            TokenType::RightParen,
-            ParseRule {
-                prefix: None,
-                infix: None,
-                precedence: Precedence::None,
-            },
-        );
-        rules.insert(
-            TokenType::LeftBrace,
-            ParseRule {
-                prefix: None,
-                infix: None,
-                precedence: Precedence::None,
-            },
-        );
-        rules.insert(
-            TokenType::RightBrace,
-            ParseRule {
-                prefix: None,
-                infix: None,
-                precedence: Precedence::None,
-            },
-        );
-        rules.insert(
-            TokenType::Comma,
-            ParseRule {
-                prefix: None,
-                infix: None,
-                precedence: Precedence::None,
-            },
-        );
-        rules.insert(
-            TokenType::Dot,
-            ParseRule {
-                prefix: None,
-                infix: None,
-                precedence: Precedence::None,
-            },
-        );
-        rules.insert(
-            TokenType::Minus,
-            ParseRule {
-                prefix: Some(Self::unary),
-                infix: Some(Self::binary),
-                precedence: Precedence::Term,
-            },
-        );
-        rules.insert(
-            TokenType::Plus,
-            ParseRule {
-                prefix: None,
-                infix: Some(Self::binary),
-                precedence: Precedence::Term,
-            },
-        );
-        rules.insert(
-            TokenType::Semicolon,
-            ParseRule {
-                prefix: None,
-                infix: None,
-                precedence: Precedence::None,
-            },
-        );
-        rules.insert(
-            TokenType::Slash,
-            ParseRule {
-                prefix: None,
-                infix: Some(Self::binary),
-                precedence: Precedence::Factor,
-            },
-        );
-        rules.insert(
-            TokenType::Star,
-            ParseRule {
-                prefix: None,
-                infix: Some(Self::binary),
-                precedence: Precedence::Factor,
-            },
-        );
-        rules.insert(
-            TokenType::Bang,
-            ParseRule {
-                prefix: Some(Self::unary),
-                infix: None,
-                precedence: Precedence::None,
-            },
-        );
-        rules.insert(
-            TokenType::BangEqual,
-            ParseRule {
-                prefix: None,
-                infix: Some(Self::binary),
-                precedence: Precedence::Equality,
-            },
-        );
-        rules.insert(
-            TokenType::Equal,
-            ParseRule {
-                prefix: None,
-                infix: None,
-                precedence: Precedence::None,
-            },
-        );
-        rules.insert(
-            TokenType::EqualEqual,
-            ParseRule {
-                prefix: None,
-                infix: Some(Self::binary),
-                precedence: Precedence::Equality,
-            },
-        );
-        rules.insert(
-            TokenType::Greater,
-            ParseRule {
-                prefix: None,
-                infix: Some(Self::binary),
-                precedence: Precedence::Comparison,
-            },
-        );
-        rules.insert(
-            TokenType::GreaterEqual,
-            ParseRule {
-                prefix: None,
-                infix: Some(Self::binary),
-                precedence: Precedence::Comparison,
-            },
-        );
-        rules.insert(
-            TokenType::Less,
-            ParseRule {
-                prefix: None,
-                infix: Some(Self::binary),
-                precedence: Precedence::Comparison,
-            },
-        );
-        rules.insert(
-            TokenType::LessEqual,
-            ParseRule {
-                prefix: None,
-                infix: Some(Self::binary),
-                precedence: Precedence::Comparison,
-            },
-        );
-        rules.insert(
-            TokenType::Identifier,
-            ParseRule {
-                prefix: Some(Self::variable),
-                infix: None,
-                precedence: Precedence::None,
-            },
-        );
-        rules.insert(
-            TokenType::String,
-            ParseRule {
-                prefix: Some(Self::string),
-                infix: None,
-                precedence: Precedence::None,
-            },
-        );
-        rules.insert(
-            TokenType::Number,
-            ParseRule {
-                prefix: Some(Self::number),
-                infix: None,
-                precedence: Precedence::None,
-            },
-        );
-        rules.insert(
-            TokenType::And,
-            ParseRule {
-                prefix: None,
-                infix: Some(Self::and),
-                precedence: Precedence::And,
-            },
-        );
-        rules.insert(
-            TokenType::Class,
-            ParseRule {
-                prefix: None,
-                infix: None,
-                precedence: Precedence::None,
-            },
-        );
-        rules.insert(
-            TokenType::Else,
-            ParseRule {
-                prefix: None,
-                infix: None,
-                precedence: Precedence::None,
-            },
-        );
-        rules.insert(
-            TokenType::False,
-            ParseRule {
-                prefix: Some(Self::literal),
-                infix: None,
-                precedence: Precedence::None,
-            },
-        );
-        rules.insert(
-            TokenType::For,
-            ParseRule {
-                prefix: None,
-                infix: None,
-                precedence: Precedence::None,
-            },
-        );
-        rules.insert(
-            TokenType::Fun,
-            ParseRule {
-                prefix: None,
-                infix: None,
-                precedence: Precedence::None,
-            },
-        );
-        rules.insert(
-            TokenType::If,
-            ParseRule {
-                prefix: None,
-                infix: None,
-                precedence: Precedence::None,
-            },
-        );
-        rules.insert(
-            TokenType::Nil,
-            ParseRule {
-                prefix: Some(Self::literal),
-                infix: None,
-                precedence: Precedence::None,
-            },
-        );
-        rules.insert(
-            TokenType::Or,
-            ParseRule {
-                prefix: None,
-                infix: Some(Self::or),
-                precedence: Precedence::Or,
-            },
-        );
-        rules.insert(
-            TokenType::Print,
-            ParseRule {
-                prefix: None,
-                infix: None,
-                precedence: Precedence::None,
-            },
-        );
-        rules.insert(
-            TokenType::Return,
-            ParseRule {
-                prefix: None,
-                infix: None,
-                precedence: Precedence::None,
-            },
-        );
-        rules.insert(
-            TokenType::Super,
-            ParseRule {
-                prefix: None,
-                infix: None,
-                precedence: Precedence::None,
-            },
-        );
-        rules.insert(
-            TokenType::This,
-            ParseRule {
-                prefix: None,
-                infix: None,
-                precedence: Precedence::None,
-            },
-        );
-        rules.insert(
-            TokenType::True,
-            ParseRule {
-                prefix: Some(Self::literal),
-                infix: None,
-                precedence: Precedence::None,
-            },
-        );
-        rules.insert(
-            TokenType::Let,
-            ParseRule {
-                prefix: None,
-                infix: None,
-                precedence: Precedence::None,
-            },
-        );
-        rules.insert(
-            TokenType::While,
-            ParseRule {
-                prefix: None,
-                infix: None,
-                precedence: Precedence::None,
-            },
-        );
-        rules.insert(
-            TokenType::Error,
-            ParseRule {
-                prefix: None,
-                infix: None,
-                precedence: Precedence::None,
-            },
-        );
+    fn final_assignment_error(&mut self, name: &Token) {
+        let message = format!("Can't assign to final variable '{}'.", token_text(name));
+        let diagnostic = Self::diagnostic_at(name, ErrorCode::AssignToFinal, &message);
+        self.error_at(diagnostic.with_note("it was declared with 'let final'."));
+    }
 
-        rules.insert(
-            TokenType::Eof,
-            ParseRule {
-                prefix: None,
-                infix: None,
-                precedence: Precedence::None,
-            },
-        );
+    fn diagnostic_at(token: &Token, code: ErrorCode, message: &str) -> Diagnostic {
+        Diagnostic::new(code, message, token.span, token.line, token.column)
+    }
 
-        rules
+    // Only the first error is kept while panicking, the ones after it are usually caused by it.
+    fn error_at(&mut self, diagnostic: Diagnostic) {
+        if self.parser.panic_mode {
+            return;
+        }
+        self.parser.panic_mode = true;
+        self.parser.had_error = true;
+        self.diagnostics.push(diagnostic);
     }
 }
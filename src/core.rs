@@ -1,17 +1,39 @@
-use crate::value::Value;
+use crate::{
+    object::{Obj, ObjRef, ObjString},
+    value::Value,
+};
 
-#[derive(Debug, Clone)]
+const TABLE_MAX_LOAD: f64 = 0.75;
+
+// A hash table using open addressing with linear probing, keyed by string objects.
+// Deleted entries leave a tombstone behind so probe sequences running through them keep working.
+#[derive(Debug, Clone, PartialEq)]
 pub struct Table {
+    // Live entries plus tombstones, so the load factor accounts for both.
     count: usize,
     entries: Vec<Entry>,
 }
 
-#[derive(Debug, Clone)]
+// An empty bucket has no key and a nil value, a tombstone has no key and a true value.
+#[derive(Debug, Clone, PartialEq)]
 pub struct Entry {
-    key: u32,
+    key: Option<ObjRef>,
     value: Value,
 }
 
+impl Entry {
+    fn empty() -> Self {
+        Self {
+            key: None,
+            value: Value::Nil,
+        }
+    }
+
+    fn is_tombstone(&self) -> bool {
+        self.key.is_none() && !self.value.is_nil()
+    }
+}
+
 impl Table {
     pub fn init() -> Self {
         Self {
@@ -25,53 +47,148 @@ impl Table {
         self.entries = Vec::new();
     }
 
-    pub fn set(&mut self, key: u32, value: Value) -> bool {
-        // println!("setting string: {:?}, {:?}", key, value);
-        let entry = self.find_entry(key);
-        let is_new_key = entry.is_none();
-        if is_new_key {
-            self.count += 1;
+    // Returns true when the key wasn't in the table yet.
+    pub fn set(&mut self, key: ObjRef, value: Value) -> bool {
+        if (self.count + 1) as f64 > self.entries.len() as f64 * TABLE_MAX_LOAD {
+            let capacity = if self.entries.len() < 8 {
+                8
+            } else {
+                self.entries.len() * 2
+            };
+            self.adjust_capacity(capacity);
         }
 
-        if is_new_key {
-            self.entries.push(Entry { key, value });
-        } else {
-            let e = self.entries.iter_mut().find(|e| e.key == key).unwrap();
-            e.key = key;
-            e.value = value;
+        let index = Self::find_entry(&self.entries, key);
+        let entry = &mut self.entries[index];
+        let is_new_key = entry.key.is_none();
+        // Reusing a tombstone doesn't change the count, it was already counted.
+        if is_new_key && entry.value.is_nil() {
+            self.count += 1;
         }
 
+        entry.key = Some(key);
+        entry.value = value;
         is_new_key
     }
 
-    pub fn get(&self, key: u32) -> Option<&Value> {
+    pub fn get(&self, key: ObjRef) -> Option<&Value> {
         if self.count == 0 {
             return None;
         }
 
-        let entry = self.find_entry(key);
-        if entry.is_some() {
-            return Some(&entry.unwrap().value);
+        let entry = &self.entries[Self::find_entry(&self.entries, key)];
+        entry.key.map(|_| &entry.value)
+    }
+
+    // Returns true when an entry was removed.
+    pub fn delete(&mut self, key: ObjRef) -> bool {
+        if self.count == 0 {
+            return false;
         }
 
-        None
+        let index = Self::find_entry(&self.entries, key);
+        let entry = &mut self.entries[index];
+        if entry.key.is_none() {
+            return false;
+        }
+
+        entry.key = None;
+        entry.value = Value::Bool(true);
+        true
     }
 
-    pub fn delete(&mut self, key: u32) -> bool {
+    // Looks a string up by its contents rather than by object, this is how strings get interned.
+    pub fn find_string(&self, chars: &[u8], hash: u32) -> Option<ObjRef> {
         if self.count == 0 {
-            return false;
+            return None;
         }
 
-        let entry = self.find_entry(key);
-        if entry.is_some() {
-            let index = self.entries.iter().position(|e| e.key == key).unwrap();
-            self.entries.remove(index);
+        let capacity = self.entries.len();
+        let mut index = hash as usize & (capacity - 1);
+        loop {
+            let entry = &self.entries[index];
+            match entry.key {
+                None if entry.is_tombstone() => {}
+                None => return None,
+                Some(key) => {
+                    let key_string = as_key_string(&key);
+                    if key_string.hash == hash && key_string.as_bytes() == chars {
+                        return Some(key);
+                    }
+                }
+            }
+
+            index = (index + 1) & (capacity - 1);
         }
+    }
+
+    // Copies every entry into the other table, used to inherit methods from a superclass.
+    pub fn add_all(&self, to: &mut Table) {
+        for entry in &self.entries {
+            if let Some(key) = entry.key {
+                to.set(key, entry.value.clone());
+            }
+        }
+    }
 
-        false
+    pub fn iter(&self) -> impl Iterator<Item = (ObjRef, &Value)> {
+        self.entries
+            .iter()
+            .filter_map(|entry| entry.key.map(|key| (key, &entry.value)))
     }
 
-    fn find_entry(&self, key: u32) -> Option<&Entry> {
-        self.entries.iter().find(|e| e.key == key)
+    // Deletes the entries whose key wasn't reached by the garbage collector,
+    // used for the string table so it doesn't keep every string alive.
+    pub fn remove_unmarked(&mut self) {
+        for entry in self.entries.iter_mut() {
+            if entry.key.is_some_and(|key| !key.is_marked()) {
+                entry.key = None;
+                entry.value = Value::Bool(true);
+            }
+        }
+    }
+
+    // Finds the bucket holding key, or the bucket it should be inserted into.
+    // Keys are interned, so comparing the objects themselves is enough.
+    // The first tombstone on the way is preferred over an empty bucket so it gets reused.
+    fn find_entry(entries: &[Entry], key: ObjRef) -> usize {
+        let capacity = entries.len();
+        let mut index = as_key_string(&key).hash as usize & (capacity - 1);
+        let mut tombstone = None;
+
+        loop {
+            let entry = &entries[index];
+            match entry.key {
+                None if entry.is_tombstone() => {
+                    tombstone.get_or_insert(index);
+                }
+                None => return tombstone.unwrap_or(index),
+                Some(existing) if existing == key => return index,
+                Some(_) => {}
+            }
+
+            index = (index + 1) & (capacity - 1);
+        }
+    }
+
+    fn adjust_capacity(&mut self, capacity: usize) {
+        let old_entries = std::mem::replace(&mut self.entries, vec![Entry::empty(); capacity]);
+
+        // Tombstones aren't copied over, so the count is rebuilt from the live entries.
+        self.count = 0;
+        for entry in old_entries {
+            if let Some(key) = entry.key {
+                let index = Self::find_entry(&self.entries, key);
+                self.entries[index] = entry;
+                self.count += 1;
+            }
+        }
+    }
+}
+
+fn as_key_string(key: &ObjRef) -> &ObjString {
+    match key.as_obj() {
+        Obj::String(obj_string) => obj_string,
+        _ => unreachable!("Table keys are always strings."),
     }
 }
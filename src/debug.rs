@@ -1,12 +1,12 @@
 use crate::chunk::{Chunk, OpCode};
 
 #[allow(dead_code)]
-pub fn disassemble_chunk(chunk: Chunk, chunk_name: &str) {
+pub fn disassemble_chunk(chunk: &Chunk, chunk_name: &str) {
     println!("== {} == \n", chunk_name);
 
     let mut offset = 0;
     while offset < chunk.code.len() {
-        offset += disassemble_instruction(&chunk, offset);
+        offset += disassemble_instruction(chunk, offset);
     }
 }
 
@@ -36,14 +36,47 @@ pub fn disassemble_instruction(chunk: &Chunk, offset: usize) -> usize {
         Some(OpCode::Return) => simple_instruction("OP_RETURN"),
         Some(OpCode::Print) => simple_instruction("OP_PRINT"),
         Some(OpCode::Pop) => simple_instruction("OP_POP"),
-        Some(OpCode::DefineGlobal) => simple_instruction("OP_DEFINE_GLOBAL"),
-        Some(OpCode::GetGlobal) => simple_instruction("OP_GET_GLOBAL"),
-        Some(OpCode::SetGlobal) => simple_instruction("OP_SET_GLOBAL"),
+        Some(OpCode::DefineGlobal) => constant_instruction("OP_DEFINE_GLOBAL", chunk, offset),
+        Some(OpCode::GetGlobal) => constant_instruction("OP_GET_GLOBAL", chunk, offset),
+        Some(OpCode::SetGlobal) => constant_instruction("OP_SET_GLOBAL", chunk, offset),
         Some(OpCode::GetLocal) => byte_instruction("OP_GET_LOCAL", chunk, offset),
         Some(OpCode::SetLocal) => byte_instruction("OP_SET_LOCAL", chunk, offset),
         Some(OpCode::JumpIfFalse) => jump_instruction("OP_JUMP_IF_FALSE", 1, chunk, offset),
         Some(OpCode::Jump) => jump_instruction("OP_JUMP", 1, chunk, offset),
-        Some(OpCode::Loop) => jump_instruction("OP_LOOP", 1, chunk, offset),
+        Some(OpCode::Loop) => jump_instruction("OP_LOOP", -1, chunk, offset),
+        Some(OpCode::Call) => byte_instruction("OP_CALL", chunk, offset),
+        Some(OpCode::Closure) => closure_instruction("OP_CLOSURE", chunk, offset),
+        Some(OpCode::GetUpvalue) => byte_instruction("OP_GET_UPVALUE", chunk, offset),
+        Some(OpCode::SetUpvalue) => byte_instruction("OP_SET_UPVALUE", chunk, offset),
+        Some(OpCode::CloseUpvalue) => simple_instruction("OP_CLOSE_UPVALUE"),
+        Some(OpCode::Class) => constant_instruction("OP_CLASS", chunk, offset),
+        Some(OpCode::GetProperty) => constant_instruction("OP_GET_PROPERTY", chunk, offset),
+        Some(OpCode::SetProperty) => constant_instruction("OP_SET_PROPERTY", chunk, offset),
+        Some(OpCode::Method) => constant_instruction("OP_METHOD", chunk, offset),
+        Some(OpCode::Inherit) => simple_instruction("OP_INHERIT"),
+        Some(OpCode::GetSuper) => constant_instruction("OP_GET_SUPER", chunk, offset),
+        Some(OpCode::BuildList) => byte_instruction("OP_BUILD_LIST", chunk, offset),
+        Some(OpCode::GetIndex) => simple_instruction("OP_GET_INDEX"),
+        Some(OpCode::SetIndex) => simple_instruction("OP_SET_INDEX"),
+        Some(OpCode::BuildMap) => byte_instruction("OP_BUILD_MAP", chunk, offset),
+        Some(OpCode::Modulo) => simple_instruction("OP_MODULO"),
+        Some(OpCode::IntDivide) => simple_instruction("OP_INT_DIVIDE"),
+        Some(OpCode::Power) => simple_instruction("OP_POWER"),
+        Some(OpCode::BitAnd) => simple_instruction("OP_BIT_AND"),
+        Some(OpCode::BitOr) => simple_instruction("OP_BIT_OR"),
+        Some(OpCode::BitXor) => simple_instruction("OP_BIT_XOR"),
+        Some(OpCode::BitNot) => simple_instruction("OP_BIT_NOT"),
+        Some(OpCode::ShiftLeft) => simple_instruction("OP_SHIFT_LEFT"),
+        Some(OpCode::ShiftRight) => simple_instruction("OP_SHIFT_RIGHT"),
+        Some(OpCode::Stringify) => simple_instruction("OP_STRINGIFY"),
+        Some(OpCode::InRange) => byte_instruction("OP_IN_RANGE", chunk, offset),
+        Some(OpCode::JumpTable) => jump_table_instruction("OP_JUMP_TABLE", chunk, offset),
+        Some(OpCode::DefineFinalGlobal) => {
+            constant_instruction("OP_DEFINE_FINAL_GLOBAL", chunk, offset)
+        }
+        Some(OpCode::Increment) => simple_instruction("OP_INCREMENT"),
+        Some(OpCode::Decrement) => simple_instruction("OP_DECREMENT"),
+        Some(OpCode::Dup) => simple_instruction("OP_DUP"),
         None => {
             println!("Unknown opcode {}", instruction);
             offset + 1
@@ -53,23 +86,64 @@ pub fn disassemble_instruction(chunk: &Chunk, offset: usize) -> usize {
 
 fn jump_instruction(name: &str, sign: isize, chunk: &Chunk, offset: usize) -> usize {
     let jump = ((chunk.code[offset + 1] as u16) << 8) | (chunk.code[offset + 2] as u16);
-    let target = offset + 3 + (sign * jump as isize) as usize;
+    let target = (offset + 3) as isize + sign * jump as isize;
 
     println!("{:<16} {:4} -> {}", name, offset, target);
 
     3
 }
 
+fn jump_table_instruction(name: &str, chunk: &Chunk, offset: usize) -> usize {
+    let lowest = chunk.constants.values[chunk.code[offset + 1] as usize]
+        .as_int()
+        .unwrap_or_default();
+    let cases = chunk.code[offset + 2] as usize;
+    let end = offset + 3 + (cases + 1) * 2;
+    let target = |i: usize| {
+        let at = offset + 3 + i * 2;
+        end - (((chunk.code[at] as usize) << 8) | chunk.code[at + 1] as usize)
+    };
+
+    println!("{:<16} {:4} cases from {}", name, cases, lowest);
+    for case in 0..cases {
+        println!("{:>27} -> {}", lowest + case as i64, target(case));
+    }
+    println!("{:>27} -> {}", "_", target(cases));
+
+    end - offset
+}
+
 fn constant_instruction(name: &str, chunk: &Chunk, offset: usize) -> usize {
     let constant_index = chunk.code[offset + 1] as usize;
     print!("{:<16} {:4} '", name, constant_index);
     if let Some(value) = chunk.constants.values.get(constant_index) {
-        print!("{}", value.value);
+        print!("{}", value);
     }
     println!("'");
     2
 }
 
+fn closure_instruction(name: &str, chunk: &Chunk, offset: usize) -> usize {
+    let constant_index = chunk.code[offset + 1] as usize;
+    let value = &chunk.constants.values[constant_index];
+    println!("{:<16} {:4} {}", name, constant_index, value);
+
+    let upvalue_count = value.as_function().map_or(0, |f| f.upvalue_count);
+    for i in 0..upvalue_count {
+        let at = offset + 2 + i * 2;
+        let is_local = chunk.code[at];
+        let index = chunk.code[at + 1];
+        println!(
+            "{:04}    |                     {} {}",
+            at,
+            if is_local == 1 { "local" } else { "upvalue" },
+            index
+        );
+    }
+
+    2 + upvalue_count * 2
+}
+
 fn simple_instruction(name: &str) -> usize {
     println!("{}", name);
     1
@@ -77,7 +151,6 @@ fn simple_instruction(name: &str) -> usize {
 
 fn byte_instruction(name: &str, chunk: &Chunk, offset: usize) -> usize {
     let slot = chunk.code[offset + 1] as usize;
-    print!("{:<16} {:4} '", name, slot);
-    println!("");
+    println!("{:<16} {:4}", name, slot);
     2
 }
@@ -0,0 +1,123 @@
+use std::fmt::Write;
+
+use crate::scanner::Span;
+
+// Identifies the kind of compile error, so tools and tests don't have to match on messages.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorCode {
+    // The scanner couldn't make a token, like an unexpected character or an unterminated string.
+    InvalidToken,
+    ExpectedToken,
+    ExpectedExpression,
+    InvalidAssignmentTarget,
+    // Something went over one of the limits of the bytecode, like 255 locals or arguments.
+    TooLarge,
+    DuplicateVariable,
+    OwnInitializer,
+    OutsideClass,
+    InvalidReturn,
+    InheritFromSelf,
+    // A literal that scans fine but whose contents are invalid, like a byte above 255
+    // or an unknown escape in a string.
+    InvalidLiteral,
+    // A 'break' or 'continue' that isn't inside a loop.
+    OutsideLoop,
+    UnknownLabel,
+    // A match without a default '_' arm, or with arms after it.
+    InvalidMatch,
+    // Assigning to or redeclaring a variable declared with 'let final'.
+    AssignToFinal,
+}
+
+impl ErrorCode {
+    pub fn code(&self) -> &'static str {
+        match self {
+            ErrorCode::InvalidToken => "E0001",
+            ErrorCode::ExpectedToken => "E0002",
+            ErrorCode::ExpectedExpression => "E0003",
+            ErrorCode::InvalidAssignmentTarget => "E0004",
+            ErrorCode::TooLarge => "E0005",
+            ErrorCode::DuplicateVariable => "E0006",
+            ErrorCode::OwnInitializer => "E0007",
+            ErrorCode::OutsideClass => "E0008",
+            ErrorCode::InvalidReturn => "E0009",
+            ErrorCode::InheritFromSelf => "E0010",
+            ErrorCode::InvalidLiteral => "E0011",
+            ErrorCode::OutsideLoop => "E0012",
+            ErrorCode::UnknownLabel => "E0013",
+            ErrorCode::InvalidMatch => "E0014",
+            ErrorCode::AssignToFinal => "E0015",
+        }
+    }
+}
+
+// A single compile error pointing at the source that caused it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Diagnostic {
+    pub code: ErrorCode,
+    pub message: String,
+    pub span: Span,
+    pub line: usize,
+    pub column: usize,
+    pub notes: Vec<String>,
+}
+
+impl Diagnostic {
+    pub fn new(code: ErrorCode, message: &str, span: Span, line: usize, column: usize) -> Self {
+        Self {
+            code,
+            message: message.to_string(),
+            span,
+            line,
+            column,
+            notes: Vec::new(),
+        }
+    }
+
+    pub fn with_note(mut self, note: &str) -> Self {
+        self.notes.push(note.to_string());
+        self
+    }
+
+    // Renders the error with the offending source line and a caret underline, like so:
+    //
+    // error[E0003]: Expect expression.
+    //  --> line 1:9
+    //   |
+    // 1 | let a = ;
+    //   |         ^
+    //   = help: ...
+    pub fn render(&self, source: &str) -> String {
+        let start = self.span.start.min(source.len());
+        let line_start = source[..start].rfind('\n').map_or(0, |i| i + 1);
+        let line_end = source[start..]
+            .find('\n')
+            .map_or(source.len(), |i| start + i);
+        let text = source[line_start..line_end].trim_end_matches('\r');
+
+        // Spans running over multiple lines are only underlined up to the end of the first one.
+        let end = self.span.end.clamp(start, line_start + text.len());
+        let padding = source[line_start..start].chars().count();
+        let width = source[start..end].chars().count().max(1);
+
+        let line_number = self.line.to_string();
+        let gutter = " ".repeat(line_number.len());
+
+        let mut out = String::new();
+        let _ = writeln!(out, "error[{}]: {}", self.code.code(), self.message);
+        let _ = writeln!(out, "{}--> line {}:{}", gutter, self.line, self.column);
+        let _ = writeln!(out, "{} |", gutter);
+        let _ = writeln!(out, "{} | {}", line_number, text);
+        let _ = writeln!(
+            out,
+            "{} | {}{}",
+            gutter,
+            " ".repeat(padding),
+            "^".repeat(width)
+        );
+        for note in &self.notes {
+            let _ = writeln!(out, "{} = help: {}", gutter, note);
+        }
+        out
+    }
+}
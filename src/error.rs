@@ -0,0 +1,66 @@
+use crate::diagnostic::Diagnostic;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorKind {
+    Compile,
+    // An operation got a value of the wrong type, like adding a number to a list.
+    Type,
+    UndefinedVariable,
+    UndefinedProperty,
+    UndefinedKey,
+    IndexOutOfRange,
+    Arity,
+    StackOverflow,
+    Final,
+    // Integer division by zero or a shift amount out of range.
+    Arithmetic,
+    // Raised by a native function.
+    Native,
+}
+
+// A function call that was active when the error happened, the innermost call comes first.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TraceFrame {
+    // None for the top-level script.
+    pub function: Option<String>,
+    pub line: usize,
+    pub column: usize,
+}
+
+// Everything that went wrong while compiling or running a script.
+// Nothing is printed when the error is created, it's up to the host how to report it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LumiError {
+    pub kind: ErrorKind,
+    pub message: String,
+    pub line: usize,
+    pub column: usize,
+    pub trace: Vec<TraceFrame>,
+    // Every error the compiler found, empty for runtime errors.
+    pub diagnostics: Vec<Diagnostic>,
+}
+
+impl LumiError {
+    pub fn new(kind: ErrorKind, message: &str, line: usize, column: usize) -> Self {
+        Self {
+            kind,
+            message: message.to_string(),
+            line,
+            column,
+            trace: Vec::new(),
+            diagnostics: Vec::new(),
+        }
+    }
+
+    pub fn compile(diagnostics: Vec<Diagnostic>) -> Self {
+        let first = &diagnostics[0];
+        Self {
+            kind: ErrorKind::Compile,
+            message: first.message.clone(),
+            line: first.line,
+            column: first.column,
+            trace: Vec::new(),
+            diagnostics,
+        }
+    }
+}
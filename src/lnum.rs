@@ -1,32 +1,54 @@
-use num_traits::{Bounded, NumCast};
+use std::{
+    cmp::Ordering,
+    ops::{Add, Mul, Sub},
+};
 
-#[derive(Debug, Clone, PartialEq)]
+use num_bigint::BigInt;
+use num_traits::{Bounded, NumCast, ToPrimitive, Zero};
+
+// Integers stay integers through arithmetic, a float on either side makes the result a float.
+// Equality and ordering compare the numeric values, so the tier or type of a number doesn't matter.
+#[allow(dead_code)]
+#[derive(Debug, Clone)]
 pub enum LNum {
     Byte(u8),
     Int(LInt),
     Float(f64),
 }
 
-#[derive(Debug, Clone, PartialEq)]
+// Why an operation on numbers couldn't produce a result.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NumError {
+    DivisionByZero,
+    // Bitwise operators only work on integers.
+    NotAnInteger,
+    ShiftOutOfRange,
+    // The integer result would have more than MAX_INT_BITS bits.
+    TooLarge,
+}
+
+// Keeps a power or shift in a one-line script from taking forever or all of the memory.
+pub const MAX_INT_BITS: u64 = 1 << 20;
+
+// The smallest tier that fits is used, results are widened when they overflow it
+// and demoted again when they fit a smaller one.
+#[derive(Debug, Clone)]
 pub enum LInt {
     Small(i16),
     Big(i32),
     Long(i64),
+    // Only used for values that don't fit in an i64.
+    // Boxed so the rare big integer doesn't make every value on the stack larger.
+    Huge(Box<BigInt>),
 }
 
 impl LNum {
-    pub fn new(n: f64) -> Self {
-        if Self::is_integer(n) {
-            LNum::Int(LInt::new(n as i64))
-        } else {
-            LNum::Float(n)
-        }
-    }
-
+    #[allow(dead_code)]
     pub fn default_int() -> LNum {
         LNum::Int(LInt::Small(0))
     }
 
+    #[allow(dead_code)]
     pub fn default_float() -> LNum {
         LNum::Float(0.0)
     }
@@ -34,25 +56,229 @@ impl LNum {
     pub fn real_val(&self) -> f64 {
         match self {
             LNum::Byte(b) => *b as f64,
-            LNum::Int(lint) => match lint {
-                LInt::Small(i) => *i as f64,
-                LInt::Big(i) => *i as f64,
-                LInt::Long(i) => *i as f64,
-            },
+            LNum::Int(lint) => lint.to_f64(),
             LNum::Float(f) => *f,
         }
     }
 
+    // The whole number value, or None for floats and integers that don't fit in an i64.
+    pub fn as_int(&self) -> Option<i64> {
+        match self {
+            LNum::Byte(b) => Some(*b as i64),
+            LNum::Int(lint) => lint.to_i64(),
+            LNum::Float(_) => None,
+        }
+    }
+
+    // The whole number value of any size, or None for floats.
+    pub fn as_big_int(&self) -> Option<BigInt> {
+        match self {
+            LNum::Byte(b) => Some(BigInt::from(*b)),
+            LNum::Int(lint) => Some(lint.to_big_int()),
+            LNum::Float(_) => None,
+        }
+    }
+
     pub fn negate(&self) -> Self {
         match self {
-            LNum::Byte(_) => panic!("Cannot negate byte."),
-            LNum::Int(lint) => LNum::Int(LInt::negate(lint)),
+            LNum::Byte(b) => LNum::Int(LInt::new(-(*b as i64))),
+            LNum::Int(lint) => match lint.to_i64().and_then(i64::checked_neg) {
+                Some(negated) => LNum::Int(LInt::new(negated)),
+                None => LNum::Int(LInt::from_big_int(-lint.to_big_int())),
+            },
             LNum::Float(f) => LNum::Float(-*f),
         }
     }
 
-    fn is_integer(n: f64) -> bool {
-        n.fract() == 0.0
+    // Runs int_op when both sides are integers, it returns None when the result doesn't fit
+    // in an i64 and big_op is tried instead. The operation is done on floats when either side
+    // is a float, or when big_op returns None because the result isn't an integer.
+    fn arithmetic(
+        &self,
+        other: &LNum,
+        int_op: fn(i64, i64) -> Option<i64>,
+        big_op: fn(BigInt, BigInt) -> Option<BigInt>,
+        float_op: fn(f64, f64) -> f64,
+    ) -> LNum {
+        if let (Some(a), Some(b)) = (self.as_int(), other.as_int()) {
+            if let Some(result) = int_op(a, b) {
+                return LNum::Int(LInt::new(result));
+            }
+        }
+        if let (Some(a), Some(b)) = (self.as_big_int(), other.as_big_int()) {
+            if let Some(result) = big_op(a, b) {
+                return LNum::Int(LInt::from_big_int(result));
+            }
+        }
+        LNum::Float(float_op(self.real_val(), other.real_val()))
+    }
+
+    // Only exact divisions stay integers, 7 / 2 is 3.5. Dividing a float by zero follows the float rules.
+    pub fn divide(&self, other: &LNum) -> Result<LNum, NumError> {
+        self.check_divisor(other)?;
+        Ok(self.arithmetic(
+            other,
+            |a, b| {
+                a.checked_rem(b)
+                    .filter(|rem| *rem == 0)
+                    .and(a.checked_div(b))
+            },
+            |a, b| (&a % &b).is_zero().then(|| a / b),
+            |a, b| a / b,
+        ))
+    }
+
+    // Divides and rounds towards zero, 7 ~/ 2 is 3.
+    pub fn int_divide(&self, other: &LNum) -> Result<LNum, NumError> {
+        self.check_divisor(other)?;
+        Ok(self.arithmetic(
+            other,
+            i64::checked_div,
+            |a, b| Some(a / b),
+            |a, b| (a / b).trunc(),
+        ))
+    }
+
+    // The remainder has the sign of the dividend, so a == (a ~/ b) * b + a % b.
+    pub fn modulo(&self, other: &LNum) -> Result<LNum, NumError> {
+        self.check_divisor(other)?;
+        Ok(self.arithmetic(other, i64::checked_rem, |a, b| Some(a % b), |a, b| a % b))
+    }
+
+    // Integers raised to a non-negative integer stay exact, anything else is a float.
+    pub fn power(&self, other: &LNum) -> Result<LNum, NumError> {
+        // Every factor of a base with n bits adds at least n - 1 bits to the result.
+        if let (Some(base), Some(exponent)) = (self.as_big_int(), other.as_int()) {
+            let bits = base.bits().saturating_sub(1);
+            if exponent > 0 && bits.saturating_mul(exponent as u64) > MAX_INT_BITS {
+                return Err(NumError::TooLarge);
+            }
+        }
+        Ok(self.arithmetic(
+            other,
+            |a, b| u32::try_from(b).ok().and_then(|b| a.checked_pow(b)),
+            |a, b| b.to_u32().map(|b| a.pow(b)),
+            f64::powf,
+        ))
+    }
+
+    pub fn bit_and(&self, other: &LNum) -> Result<LNum, NumError> {
+        self.bitwise(other, |a, b| a & b, |a, b| a & b)
+    }
+
+    pub fn bit_or(&self, other: &LNum) -> Result<LNum, NumError> {
+        self.bitwise(other, |a, b| a | b, |a, b| a | b)
+    }
+
+    pub fn bit_xor(&self, other: &LNum) -> Result<LNum, NumError> {
+        self.bitwise(other, |a, b| a ^ b, |a, b| a ^ b)
+    }
+
+    // Same as -a - 1 in two's complement, which also works for big integers.
+    pub fn bit_not(&self) -> Result<LNum, NumError> {
+        match self {
+            LNum::Float(_) => Err(NumError::NotAnInteger),
+            _ => Ok(self.negate() - LNum::Int(LInt::Small(1))),
+        }
+    }
+
+    // Shifting left never overflows, the result grows into a bigger tier instead.
+    pub fn shift_left(&self, other: &LNum) -> Result<LNum, NumError> {
+        let value = self.as_big_int().ok_or(NumError::NotAnInteger)?;
+        let amount = Self::shift_amount(other)?;
+        if !value.is_zero() && value.bits() + amount as u64 > MAX_INT_BITS {
+            return Err(NumError::TooLarge);
+        }
+        if let Some(a) = self.as_int() {
+            if amount < i64::BITS && (a << amount) >> amount == a {
+                return Ok(LNum::Int(LInt::new(a << amount)));
+            }
+        }
+        Ok(LNum::Int(LInt::from_big_int(value << amount)))
+    }
+
+    // An arithmetic shift, negative numbers stay negative.
+    pub fn shift_right(&self, other: &LNum) -> Result<LNum, NumError> {
+        let value = self.as_big_int().ok_or(NumError::NotAnInteger)?;
+        let amount = Self::shift_amount(other)?;
+        if let Some(a) = self.as_int() {
+            return Ok(LNum::Int(LInt::new(a >> amount.min(i64::BITS - 1))));
+        }
+        Ok(LNum::Int(LInt::from_big_int(value >> amount)))
+    }
+
+    fn check_divisor(&self, other: &LNum) -> Result<(), NumError> {
+        if self.as_big_int().is_some() && other.as_int() == Some(0) {
+            return Err(NumError::DivisionByZero);
+        }
+        Ok(())
+    }
+
+    fn bitwise(
+        &self,
+        other: &LNum,
+        int_op: fn(i64, i64) -> i64,
+        big_op: fn(BigInt, BigInt) -> BigInt,
+    ) -> Result<LNum, NumError> {
+        if let (Some(a), Some(b)) = (self.as_int(), other.as_int()) {
+            return Ok(LNum::Int(LInt::new(int_op(a, b))));
+        }
+        match (self.as_big_int(), other.as_big_int()) {
+            (Some(a), Some(b)) => Ok(LNum::Int(LInt::from_big_int(big_op(a, b)))),
+            _ => Err(NumError::NotAnInteger),
+        }
+    }
+
+    fn shift_amount(amount: &LNum) -> Result<u32, NumError> {
+        match amount {
+            LNum::Float(_) => Err(NumError::NotAnInteger),
+            _ => amount
+                .as_int()
+                .and_then(|amount| u32::try_from(amount).ok())
+                .ok_or(NumError::ShiftOutOfRange),
+        }
+    }
+}
+
+impl Add for LNum {
+    type Output = LNum;
+
+    fn add(self, other: LNum) -> LNum {
+        self.arithmetic(&other, i64::checked_add, |a, b| Some(a + b), |a, b| a + b)
+    }
+}
+
+impl Sub for LNum {
+    type Output = LNum;
+
+    fn sub(self, other: LNum) -> LNum {
+        self.arithmetic(&other, i64::checked_sub, |a, b| Some(a - b), |a, b| a - b)
+    }
+}
+
+impl Mul for LNum {
+    type Output = LNum;
+
+    fn mul(self, other: LNum) -> LNum {
+        self.arithmetic(&other, i64::checked_mul, |a, b| Some(a * b), |a, b| a * b)
+    }
+}
+
+impl PartialEq for LNum {
+    fn eq(&self, other: &LNum) -> bool {
+        self.partial_cmp(other) == Some(Ordering::Equal)
+    }
+}
+
+impl PartialOrd for LNum {
+    fn partial_cmp(&self, other: &LNum) -> Option<Ordering> {
+        if let (Some(a), Some(b)) = (self.as_int(), other.as_int()) {
+            return Some(a.cmp(&b));
+        }
+        match (self.as_big_int(), other.as_big_int()) {
+            (Some(a), Some(b)) => Some(a.cmp(&b)),
+            _ => self.real_val().partial_cmp(&other.real_val()),
+        }
     }
 }
 
@@ -75,11 +301,34 @@ impl LInt {
         }
     }
 
-    pub fn negate(&self) -> Self {
+    pub fn from_big_int(i: BigInt) -> Self {
+        match i.to_i64() {
+            Some(i) => LInt::new(i),
+            None => LInt::Huge(Box::new(i)),
+        }
+    }
+
+    // None when the value doesn't fit in an i64, which only happens for Huge.
+    pub fn to_i64(&self) -> Option<i64> {
         match self {
-            LInt::Small(s) => LInt::Small(-*s),
-            LInt::Big(b) => LInt::Big(-*b),
-            LInt::Long(l) => LInt::Long(-*l),
+            LInt::Small(s) => Some(*s as i64),
+            LInt::Big(b) => Some(*b as i64),
+            LInt::Long(l) => Some(*l),
+            LInt::Huge(_) => None,
+        }
+    }
+
+    pub fn to_big_int(&self) -> BigInt {
+        match self {
+            LInt::Huge(h) => (**h).clone(),
+            _ => BigInt::from(self.to_i64().unwrap()),
+        }
+    }
+
+    pub fn to_f64(&self) -> f64 {
+        match self {
+            LInt::Huge(h) => h.to_f64().unwrap_or(f64::NAN),
+            _ => self.to_i64().unwrap() as f64,
         }
     }
 
@@ -90,12 +339,22 @@ impl LInt {
     }
 }
 
+impl PartialEq for LInt {
+    fn eq(&self, other: &LInt) -> bool {
+        match (self.to_i64(), other.to_i64()) {
+            (Some(a), Some(b)) => a == b,
+            _ => self.to_big_int() == other.to_big_int(),
+        }
+    }
+}
+
 impl std::fmt::Display for LInt {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             LInt::Small(s) => write!(f, "{}", s),
             LInt::Big(b) => write!(f, "{}", b),
             LInt::Long(l) => write!(f, "{}", l),
+            LInt::Huge(h) => write!(f, "{}", h),
         }
     }
 }
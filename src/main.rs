@@ -4,6 +4,7 @@ use std::{
     path::Path,
 };
 
+use error::{ErrorKind, LumiError};
 use sysinfo::System;
 use vm::VM;
 
@@ -11,7 +12,11 @@ mod chunk;
 mod compiler;
 mod core;
 mod debug;
+mod diagnostic;
+mod error;
 mod lnum;
+mod memory;
+mod natives;
 mod object;
 mod scanner;
 mod utils;
@@ -39,8 +44,9 @@ fn main() {
 fn repl(vm: &mut VM, _sysinfo: &System) {
     let mut input = String::new();
     while prompt(&mut input) {
-        let input_ref: &'static str = Box::leak(input.clone().into_boxed_str());
-        benchmark!(vm.interpret(input_ref.trim_end()));
+        if let Err(error) = benchmark!(vm.interpret(input.trim_end())) {
+            report_error(&error, input.trim_end());
+        }
 
         #[cfg(feature = "bench")]
         if let Some(proc) = _sysinfo.process(sysinfo::get_current_pid().unwrap()) {
@@ -54,10 +60,29 @@ fn repl(vm: &mut VM, _sysinfo: &System) {
 
 fn run_code(code: &str) {
     let mut vm = VM::init_vm();
-    benchmark!(vm.interpret(code));
+    if let Err(error) = benchmark!(vm.interpret(code)) {
+        report_error(&error, code);
+    }
     vm.free_vm();
 }
 
+fn report_error(error: &LumiError, source: &str) {
+    if error.kind == ErrorKind::Compile {
+        for diagnostic in &error.diagnostics {
+            eprint!("{}", diagnostic.render(source));
+        }
+        return;
+    }
+
+    eprintln!("{}", error.message);
+    for frame in &error.trace {
+        match &frame.function {
+            Some(name) => eprintln!("[line {}:{}] in {}()", frame.line, frame.column, name),
+            None => eprintln!("[line {}:{}] in script", frame.line, frame.column),
+        }
+    }
+}
+
 fn prompt(input: &mut String) -> bool {
     input.clear();
     print!("lumi> ");
@@ -65,36 +90,29 @@ fn prompt(input: &mut String) -> bool {
         return false;
     }
 
-    match stdin().read_line(input) {
-        Ok(_) => true,
-        Err(_) => false,
-    }
+    // Reading nothing means stdin was closed.
+    matches!(stdin().read_line(input), Ok(read) if read > 0)
 }
 
 #[macro_export]
 macro_rules! benchmark {
-    ($expr:expr) => {
+    ($expr:expr) => {{
         #[cfg(feature = "bench")]
-        {
-            let start = std::time::Instant::now();
-            let result = $expr;
-            let duration = start.elapsed();
-            println!("Execution time: {}µs", duration.as_micros());
-            result
-        }
-        #[cfg(not(feature = "bench"))]
-        {
-            $expr
-        }
-    };
+        let start = std::time::Instant::now();
+        let result = $expr;
+        #[cfg(feature = "bench")]
+        println!("Execution time: {}µs", start.elapsed().as_micros());
+        result
+    }};
 }
 
 #[cfg(test)]
 mod test {
 
     use crate::{
+        diagnostic::ErrorCode,
+        error::ErrorKind,
         lnum::{LInt, LNum},
-        object::{Obj, ObjString},
         value::Value,
         vm::VM,
     };
@@ -103,7 +121,7 @@ mod test {
     fn binary_op_add() {
         let code: &str = "print 1 + 1;\n";
         let mut vm = VM::init_vm();
-        vm.interpret(&code);
+        vm.interpret(code).unwrap();
         assert_eq!(
             vm.test_values.pop().unwrap(),
             Value::Number(LNum::Int(LInt::Small(2)))
@@ -114,7 +132,7 @@ mod test {
     fn equals_int() {
         let code: &str = "print 3 + 7 == 10;\n";
         let mut vm = VM::init_vm();
-        vm.interpret(&code);
+        vm.interpret(code).unwrap();
         assert_eq!(vm.test_values.pop().unwrap(), Value::Bool(true));
     }
 
@@ -122,37 +140,996 @@ mod test {
     fn print_string() {
         let code: &str = "print \"abc\";\n";
         let mut vm = VM::init_vm();
-        vm.interpret(&code);
+        vm.interpret(code).unwrap();
+        assert_eq!(vm.test_values.pop().unwrap().as_c_string(), Some("abc"));
+    }
+
+    #[test]
+    fn concat_strings() {
+        let code: &str = "print \"a\" + \"b\";\n";
+        let mut vm = VM::init_vm();
+        vm.interpret(code).unwrap();
+        assert_eq!(vm.test_values.pop().unwrap().as_c_string(), Some("ab"));
+    }
+
+    #[test]
+    fn equals_string() {
+        let code: &str = "print \"test\" + \"a\" == \"testa\";\n";
+        let mut vm = VM::init_vm();
+        vm.interpret(code).unwrap();
+        assert_eq!(vm.test_values.pop().unwrap(), Value::Bool(true));
+    }
+
+    #[test]
+    fn runtime_error_has_stack_trace() {
+        let code: &str =
+            "fun inner() {\n  return 1 + nil;\n}\nfun outer() { inner(); }\nouter();\n";
+        let mut vm = VM::init_vm();
+        let error = vm.interpret(code).unwrap_err();
+        assert_eq!(error.kind, ErrorKind::Type);
+        assert_eq!(
+            error.message,
+            "Operands must be two numbers or two strings."
+        );
+        assert_eq!((error.line, error.column), (2, 14));
+
+        let functions: Vec<Option<&str>> = error
+            .trace
+            .iter()
+            .map(|frame| frame.function.as_deref())
+            .collect();
+        assert_eq!(functions, vec![Some("inner"), Some("outer"), None]);
+        assert_eq!(error.trace[2].line, 5);
+    }
+
+    #[test]
+    fn call_function() {
+        let code: &str = "fun add(a, b) { return a + b; }\nprint add(3, 4);\n";
+        let mut vm = VM::init_vm();
+        vm.interpret(code).unwrap();
         assert_eq!(
             vm.test_values.pop().unwrap(),
-            Value::Object(Box::new(Obj::String(ObjString::new(
-                "abc".as_bytes(),
-                "abc".as_bytes().len()
-            ))))
+            Value::Number(LNum::Int(LInt::Small(7)))
         );
     }
 
     #[test]
-    fn concat_strings() {
-        let code: &str = "print \"a\" + \"b\";\n";
+    fn recursive_function() {
+        let code: &str =
+            "fun fib(n) { if (n < 2) return n; return fib(n - 1) + fib(n - 2); }\nprint fib(10);\n";
         let mut vm = VM::init_vm();
-        vm.interpret(&code);
+        vm.interpret(code).unwrap();
         assert_eq!(
             vm.test_values.pop().unwrap(),
-            Value::Object(Box::new(Obj::String(ObjString::new(
-                "ab".as_bytes(),
-                "ab".as_bytes().len()
-            ))))
+            Value::Number(LNum::Int(LInt::Small(55)))
         );
     }
 
     #[test]
-    fn equals_string() {
-        let code: &str = "print \"test\" + \"a\" == \"testa\";\n";
+    fn function_without_return_is_nil() {
+        let code: &str = "fun f() { let a = 1; }\nprint f();\n";
+        let mut vm = VM::init_vm();
+        vm.interpret(code).unwrap();
+        assert_eq!(vm.test_values.pop().unwrap(), Value::Nil);
+    }
+
+    #[test]
+    fn wrong_arity() {
+        let code: &str = "fun f(a) { return a; }\nf(1, 2);\n";
+        let mut vm = VM::init_vm();
+        assert_eq!(vm.interpret(code).unwrap_err().kind, ErrorKind::Arity);
+    }
+
+    #[test]
+    fn closure_counter() {
+        let code: &str = "fun makeCounter() {\n  let c = 0;\n  fun inc() { c = c + 1; return c; }\n  return inc;\n}\nlet counter = makeCounter();\ncounter();\nprint counter();\n";
+        let mut vm = VM::init_vm();
+        vm.interpret(code).unwrap();
+        assert_eq!(
+            vm.test_values.pop().unwrap(),
+            Value::Number(LNum::Int(LInt::Small(2)))
+        );
+    }
+
+    #[test]
+    fn closures_share_upvalue() {
+        let code: &str = "let get;\nlet set;\n{\n  let shared = 1;\n  fun g() { return shared; }\n  fun s(v) { shared = v; }\n  get = g;\n  set = s;\n}\nset(42);\nprint get();\n";
         let mut vm = VM::init_vm();
-        vm.interpret(&code);
+        vm.interpret(code).unwrap();
+        assert_eq!(
+            vm.test_values.pop().unwrap(),
+            Value::Number(LNum::Int(LInt::Small(42)))
+        );
+    }
+
+    #[test]
+    fn class_initializer_and_method() {
+        let code: &str = "class Point {\n  init(x, y) { this.x = x; this.y = y; }\n  sum() { return this.x + this.y; }\n}\nprint Point(3, 4).sum();\n";
+        let mut vm = VM::init_vm();
+        vm.interpret(code).unwrap();
+        assert_eq!(
+            vm.test_values.pop().unwrap(),
+            Value::Number(LNum::Int(LInt::Small(7)))
+        );
+    }
+
+    #[test]
+    fn bound_method_keeps_this() {
+        let code: &str = "class A {\n  name() { return this.n; }\n}\nlet a = A();\na.n = \"lumi\";\nlet m = a.name;\nprint m();\n";
+        let mut vm = VM::init_vm();
+        vm.interpret(code).unwrap();
+        assert_eq!(vm.test_values.pop().unwrap().as_c_string(), Some("lumi"));
+    }
+
+    #[test]
+    fn undefined_property() {
+        let code: &str = "class A {}\nA().missing;\n";
+        let mut vm = VM::init_vm();
+        assert_eq!(
+            vm.interpret(code).unwrap_err().kind,
+            ErrorKind::UndefinedProperty
+        );
+    }
+
+    #[test]
+    fn this_outside_class() {
+        let code: &str = "print this;\n";
+        let mut vm = VM::init_vm();
+        assert_eq!(vm.interpret(code).unwrap_err().kind, ErrorKind::Compile);
+    }
+
+    #[test]
+    fn inherited_method_and_super_call() {
+        let code: &str = "class A {\n  name() { return \"A\"; }\n}\nclass B < A {\n  name() { return \"B\" + super.name(); }\n}\nclass C < B {}\nprint C().name();\n";
+        let mut vm = VM::init_vm();
+        vm.interpret(code).unwrap();
+        assert_eq!(vm.test_values.pop().unwrap().as_c_string(), Some("BA"));
+    }
+
+    #[test]
+    fn inherit_from_non_class() {
+        let code: &str = "let a = 1;\nclass B < a {}\n";
+        let mut vm = VM::init_vm();
+        assert_eq!(vm.interpret(code).unwrap_err().kind, ErrorKind::Type);
+    }
+
+    #[test]
+    fn super_without_superclass() {
+        let code: &str = "class A {\n  m() { return super.m(); }\n}\nA().m();\n";
+        let mut vm = VM::init_vm();
+        assert_eq!(vm.interpret(code).unwrap_err().kind, ErrorKind::Type);
+    }
+
+    #[test]
+    fn return_from_top_level() {
+        let code: &str = "return 1;\n";
+        let mut vm = VM::init_vm();
+        assert_eq!(vm.interpret(code).unwrap_err().kind, ErrorKind::Compile);
+    }
+
+    #[test]
+    fn list_index_and_negative_index() {
+        let code: &str = "let a = [1, 2, 3];\nprint a[0] + a[-1];\n";
+        let mut vm = VM::init_vm();
+        vm.interpret(code).unwrap();
+        assert_eq!(
+            vm.test_values.pop().unwrap(),
+            Value::Number(LNum::Int(LInt::Small(4)))
+        );
+    }
+
+    #[test]
+    fn list_index_assignment() {
+        let code: &str = "let a = [1, 2, 3];\na[1] = \"two\";\nprint a[1];\n";
+        let mut vm = VM::init_vm();
+        vm.interpret(code).unwrap();
+        assert_eq!(vm.test_values.pop().unwrap().as_c_string(), Some("two"));
+    }
+
+    #[test]
+    fn list_index_out_of_range() {
+        let code: &str = "let a = [1, 2, 3];\nprint a[3];\n";
+        let mut vm = VM::init_vm();
+        assert_eq!(
+            vm.interpret(code).unwrap_err().kind,
+            ErrorKind::IndexOutOfRange
+        );
+    }
+
+    #[test]
+    fn list_push_pop_len() {
+        let code: &str = "let a = [];\npush(a, 1);\npush(a, 2);\npop(a);\nprint len(a);\n";
+        let mut vm = VM::init_vm();
+        vm.interpret(code).unwrap();
+        assert_eq!(
+            vm.test_values.pop().unwrap(),
+            Value::Number(LNum::Int(LInt::Small(1)))
+        );
+    }
+
+    #[test]
+    fn map_lookup_and_assignment() {
+        let code: &str =
+            "let m = { \"a\": 1, 2: \"two\" };\nm[\"a\"] = m[\"a\"] + 1;\nprint m[\"a\"];\n";
+        let mut vm = VM::init_vm();
+        vm.interpret(code).unwrap();
+        assert_eq!(
+            vm.test_values.pop().unwrap(),
+            Value::Number(LNum::Int(LInt::Small(2)))
+        );
+    }
+
+    #[test]
+    fn map_keys_distinguish_types() {
+        let code: &str =
+            "let m = { \"1\": \"string\", 1: \"number\", true: \"bool\" };\nprint m[1];\n";
+        let mut vm = VM::init_vm();
+        vm.interpret(code).unwrap();
+        assert_eq!(vm.test_values.pop().unwrap().as_c_string(), Some("number"));
+    }
+
+    #[test]
+    fn map_remove_and_has() {
+        let code: &str =
+            "let m = { \"a\": 1, \"b\": 2 };\nremove(m, \"a\");\nprint has(m, \"a\");\n";
+        let mut vm = VM::init_vm();
+        vm.interpret(code).unwrap();
+        assert_eq!(vm.test_values.pop().unwrap(), Value::Bool(false));
+    }
+
+    #[test]
+    fn map_missing_key() {
+        let code: &str = "let m = { \"a\": 1 };\nprint m[\"b\"];\n";
+        let mut vm = VM::init_vm();
+        assert_eq!(
+            vm.interpret(code).unwrap_err().kind,
+            ErrorKind::UndefinedKey
+        );
+    }
+
+    #[test]
+    fn globals_with_colliding_hashes() {
+        // "glbvs" and "yacxa" share the same FNV-1a hash.
+        let code: &str = "let glbvs = 1;\nlet yacxa = 2;\nprint glbvs;\n";
+        let mut vm = VM::init_vm();
+        vm.interpret(code).unwrap();
+        assert_eq!(
+            vm.test_values.pop().unwrap(),
+            Value::Number(LNum::Int(LInt::Small(1)))
+        );
+    }
+
+    #[test]
+    fn assign_undefined_global_leaves_it_undefined() {
+        let code: &str = "a = 1;\n";
+        let mut vm = VM::init_vm();
+        assert_eq!(
+            vm.interpret(code).unwrap_err().kind,
+            ErrorKind::UndefinedVariable
+        );
+        assert_eq!(
+            vm.interpret("print a;\n").unwrap_err().kind,
+            ErrorKind::UndefinedVariable
+        );
+    }
+
+    #[test]
+    fn interned_strings_share_object() {
+        let code: &str = "print \"ab\";\nprint \"a\" + \"b\";\nprint \"a\" + \"b\" == \"ab\";\n";
+        let mut vm = VM::init_vm();
+        vm.interpret(code).unwrap();
+        assert_eq!(vm.test_values.pop().unwrap(), Value::Bool(true));
+        // Values compare objects by identity, so this only holds when both are one object.
+        assert_eq!(vm.test_values.pop().unwrap(), vm.test_values.pop().unwrap());
+    }
+
+    #[test]
+    fn garbage_is_collected() {
+        let code: &str = "let i = 0;\nwhile (i < 10000) {\n  let garbage = [i, [i]];\n  i = i + 1;\n}\nlet kept = [1, 2];\nprint kept;\n";
+        let mut vm = VM::init_vm();
+        vm.set_gc_threshold(4096);
+        vm.interpret(code).unwrap();
+        assert!(vm.bytes_allocated() < 64 * 1024, "{}", vm.bytes_allocated());
+        assert_eq!(vm.test_values.pop().unwrap().to_string(), "[1, 2]");
+    }
+
+    #[test]
+    fn growing_lists_and_maps_count_towards_the_heap() {
+        let mut vm = VM::init_vm();
+        vm.interpret("let l = [];\nlet m = {};\n").unwrap();
+        let before = vm.bytes_allocated();
+        vm.interpret("for (let i = 0; i < 10000; i++) { push(l, i); m[i] = i; }\n")
+            .unwrap();
+        let grown = vm.bytes_allocated() - before;
+        assert!(grown > 10000 * 3 * size_of::<Value>(), "{}", grown);
+
+        vm.interpret("l = nil;\nm = nil;\n").unwrap();
+        vm.set_gc_threshold(0);
+        vm.interpret("let collect = [];\n").unwrap();
+        assert!(
+            vm.bytes_allocated() < before + 4096,
+            "{}",
+            vm.bytes_allocated()
+        );
+    }
+
+    #[test]
+    fn printed_values_survive_collections_while_compiling() {
+        let mut vm = VM::init_vm();
+        vm.set_gc_threshold(0);
+        vm.interpret("print [1, 2];\n").unwrap();
+        vm.interpret("let y = [3];\nprint y;\n").unwrap();
+        let values: Vec<String> = vm.test_values.iter().map(|v| v.to_string()).collect();
+        assert_eq!(values, vec!["[1, 2]", "[3]"]);
+    }
+
+    #[test]
+    fn list_containing_itself() {
+        let code: &str = "let a = [1];\npush(a, a);\nprint a;\nprint a == a;\nlet b = [1];\npush(b, b);\nprint a == b;\nprint [a] == [a];\n";
+        assert_eq!(printed(code), vec!["[1, [...]]", "true", "false", "true"]);
+    }
+
+    #[test]
+    fn map_containing_itself_and_string_keys() {
+        let code: &str = "let m = {};\nm[\"x\"] = m;\nprint m;\nprint m == m;\nprint {\"1\": \"a\", 1: [\"b\"]};\n";
+        assert_eq!(
+            printed(code),
+            vec!["{\"x\": {...}}", "true", "{\"1\": \"a\", 1: [\"b\"]}"]
+        );
+    }
+
+    #[test]
+    fn removing_map_entries_keeps_insertion_order() {
+        let code: &str = "let m = {};\nfor (let i = 0; i < 100; i++) { m[i] = i; }\nfor (let i = 0; i < 98; i++) { remove(m, i); }\nm[0] = 0;\nprint m;\nprint len(m);\nprint m[99];\n";
+        assert_eq!(printed(code), vec!["{98: 98, 99: 99, 0: 0}", "3", "99"]);
+    }
+
+    #[test]
+    fn list_equality() {
+        let code: &str = "print [1, \"a\", [2]] == [1, \"a\", [2]];\n";
+        let mut vm = VM::init_vm();
+        vm.interpret(code).unwrap();
         assert_eq!(vm.test_values.pop().unwrap(), Value::Bool(true));
     }
+
+    #[test]
+    fn compile_error_diagnostic() {
+        let code: &str = "let a = 1;
+let b = ;
+";
+        let mut vm = VM::init_vm();
+        let error = vm.interpret(code).unwrap_err();
+        assert_eq!(error.kind, ErrorKind::Compile);
+        assert_eq!((error.line, error.column), (2, 9));
+
+        let diagnostic = &error.diagnostics[0];
+        assert_eq!(diagnostic.code, ErrorCode::ExpectedExpression);
+        assert_eq!(&code[diagnostic.span.start..diagnostic.span.end], ";");
+    }
+
+    #[test]
+    fn render_diagnostic() {
+        let code: &str = "let a = 1;
+1 + a = 2;
+";
+        let mut vm = VM::init_vm();
+        let error = vm.interpret(code).unwrap_err();
+        let rendered = error.diagnostics[0].render(code);
+        assert_eq!(
+            rendered,
+            "error[E0004]: Invalid assignment target.\n --> line 2:7\n  |\n2 | 1 + a = 2;\n  |       ^\n  = help: only variables, properties and index expressions can be assigned to.\n"
+        );
+    }
+
+    #[test]
+    fn integer_arithmetic_keeps_precision() {
+        let code: &str = "print 9007199254740993 + 0;\nprint 4611686018427387903 * 2 + 1;\n";
+        let mut vm = VM::init_vm();
+        vm.interpret(code).unwrap();
+        assert_eq!(
+            vm.test_values.pop().unwrap().to_string(),
+            "9223372036854775807"
+        );
+        assert_eq!(
+            vm.test_values.pop().unwrap().to_string(),
+            "9007199254740993"
+        );
+    }
+
+    #[test]
+    fn deep_recursion_is_a_stack_overflow() {
+        let mut vm = VM::init_vm();
+        let error = vm
+            .interpret("fun f(n) { return f(n + 1); }\nf(0);\n")
+            .unwrap_err();
+        assert_eq!(error.kind, ErrorKind::StackOverflow);
+
+        // Every frame holds 400 temporaries, so the stack fills up before the frames run out.
+        let items = vec!["n"; 200].join(", ");
+        let code = format!(
+            "fun f(n) {{ return [{}, [{}, f(n + 1)]]; }}\nf(0);\n",
+            items, items
+        );
+        let mut vm = VM::init_vm();
+        assert_eq!(
+            vm.interpret(&code).unwrap_err().kind,
+            ErrorKind::StackOverflow
+        );
+        vm.interpret("print 1;\n").unwrap();
+    }
+
+    #[test]
+    fn integer_overflow_widens() {
+        let code: &str = "print 32767 + 1;\nprint 2147483647 + 1;\nprint -32768 - 1;\n";
+        let mut vm = VM::init_vm();
+        vm.interpret(code).unwrap();
+        let values: Vec<Value> = vm.test_values.drain(..).collect();
+        assert!(matches!(
+            values[0],
+            Value::Number(LNum::Int(LInt::Big(32768)))
+        ));
+        assert!(matches!(
+            values[1],
+            Value::Number(LNum::Int(LInt::Long(2147483648)))
+        ));
+        assert!(matches!(
+            values[2],
+            Value::Number(LNum::Int(LInt::Big(-32769)))
+        ));
+    }
+
+    #[test]
+    fn division_and_float_contagion() {
+        let code: &str = "print 6 / 3;\nprint 7 / 2;\nprint 7 / 2 * 2;\n";
+        let mut vm = VM::init_vm();
+        vm.interpret(code).unwrap();
+        let values: Vec<Value> = vm.test_values.drain(..).collect();
+        assert!(matches!(
+            values[0],
+            Value::Number(LNum::Int(LInt::Small(2)))
+        ));
+        assert!(matches!(values[1], Value::Number(LNum::Float(f)) if f == 3.5));
+        assert!(matches!(values[2], Value::Number(LNum::Float(f)) if f == 7.0));
+    }
+
+    #[test]
+    fn numbers_equal_across_tiers() {
+        let code: &str = "print 100000 - 99999 == 1;\nprint 7 / 2 * 2 == 7;\nprint 40000 > 3;\n";
+        let mut vm = VM::init_vm();
+        vm.interpret(code).unwrap();
+        assert_eq!(vm.test_values, vec![Value::Bool(true); 3]);
+        assert_eq!(
+            Value::Number(LNum::Int(LInt::Small(2))),
+            Value::Number(LNum::Int(LInt::Big(2)))
+        );
+    }
+
+    #[test]
+    fn huge_integer_factorial() {
+        let code: &str = "fun fact(n) { if (n < 2) return 1; return n * fact(n - 1); }\nprint fact(30);\nprint fact(30) / fact(28);\n";
+        let mut vm = VM::init_vm();
+        vm.interpret(code).unwrap();
+        let values: Vec<Value> = vm.test_values.drain(..).collect();
+        assert!(matches!(values[0], Value::Number(LNum::Int(LInt::Huge(_)))));
+        assert_eq!(values[0].to_string(), "265252859812191058636308480000000");
+        assert!(matches!(
+            values[1],
+            Value::Number(LNum::Int(LInt::Small(870)))
+        ));
+    }
+
+    #[test]
+    fn huge_integer_literals_and_comparison() {
+        let code: &str = "let h = 99999999999999999999;\nprint h + 1;\nprint h > 9223372036854775807;\nprint -9223372036854775807 - 2;\nprint h - h + 1 == 1;\n";
+        let mut vm = VM::init_vm();
+        vm.interpret(code).unwrap();
+        let values: Vec<String> = vm.test_values.iter().map(|v| v.to_string()).collect();
+        assert_eq!(
+            values,
+            vec![
+                "100000000000000000000",
+                "true",
+                "-9223372036854775809",
+                "true"
+            ]
+        );
+    }
+
+    #[test]
+    fn huge_integer_map_key() {
+        let code: &str =
+            "let m = {};\nm[10000000000000000000 * 10] = 1;\nprint m[100000000000000000000];\n";
+        let mut vm = VM::init_vm();
+        vm.interpret(code).unwrap();
+        assert_eq!(vm.test_values.pop().unwrap().to_string(), "1");
+    }
+
+    fn printed(code: &str) -> Vec<String> {
+        let mut vm = VM::init_vm();
+        vm.interpret(code).unwrap();
+        vm.test_values.iter().map(|v| v.to_string()).collect()
+    }
+
+    #[test]
+    fn modulo_and_integer_division() {
+        let code: &str =
+            "print 7 % 3;\nprint -7 % 3;\nprint 7 ~/ 2;\nprint -7 ~/ 2;\nprint 7 / 2 ~/ 1;\n";
+        assert_eq!(printed(code), vec!["1", "-1", "3", "-3", "3"]);
+    }
+
+    #[test]
+    fn power_is_right_associative_and_binds_tighter_than_unary() {
+        let code: &str = "print 2 ** 3 ** 2;\nprint -2 ** 2;\nprint 2 ** -1;\nprint 2 ** 100;\n";
+        assert_eq!(
+            printed(code),
+            vec!["512", "-4", "0.5", "1267650600228229401496703205376"]
+        );
+    }
+
+    #[test]
+    fn bitwise_operators() {
+        let code: &str = "print 6 & 3;\nprint 6 | 3;\nprint 6 ^ 3;\nprint ~5;\nprint 1 << 70;\nprint (1 << 70) >> 68;\nprint -8 >> 1;\nprint 1 | 2 == 3;\n";
+        assert_eq!(
+            printed(code),
+            vec![
+                "2",
+                "7",
+                "5",
+                "-6",
+                "1180591620717411303424",
+                "4",
+                "-4",
+                "true"
+            ]
+        );
+    }
+
+    #[test]
+    fn bitwise_on_float_is_type_error() {
+        let mut vm = VM::init_vm();
+        let error = vm.interpret("print (1 / 2) & 1;\n").unwrap_err();
+        assert_eq!(error.kind, ErrorKind::Type);
+        let error = vm.interpret("print ~(1 / 2);\n").unwrap_err();
+        assert_eq!(error.kind, ErrorKind::Type);
+    }
+
+    #[test]
+    fn integer_division_by_zero() {
+        for code in ["print 1 / 0;\n", "print 1 ~/ 0;\n", "\nprint 1 % 0;\n"] {
+            let mut vm = VM::init_vm();
+            let error = vm.interpret(code).unwrap_err();
+            assert_eq!(error.kind, ErrorKind::Arithmetic);
+            assert_eq!(error.line, code.matches('\n').count());
+        }
+    }
+
+    #[test]
+    fn huge_integer_results_are_an_error() {
+        for code in ["print 2 ** 4000000000;\n", "print 1 << 4294967295;\n"] {
+            let mut vm = VM::init_vm();
+            assert_eq!(vm.interpret(code).unwrap_err().kind, ErrorKind::Arithmetic);
+        }
+        let code: &str = "print 1 ** 4000000000;\nprint (-1) ** 4000000001;\nprint 0 << 4294967295;\nprint len(\"${2 ** 100000}\");\n";
+        assert_eq!(printed(code), vec!["1", "-1", "0", "30103"]);
+    }
+
+    #[test]
+    fn number_literals() {
+        let code: &str = "print 3.14;\nprint 0x1F;\nprint 0b1010;\nprint 0o17;\nprint 1_000_000;\nprint 1e-3;\nprint 2.5E10;\nprint 0xFFFF_FFFF_FFFF_FFFF_FF;\n";
+        assert_eq!(
+            printed(code),
+            vec![
+                "3.14",
+                "31",
+                "10",
+                "15",
+                "1000000",
+                "0.001",
+                "25000000000",
+                "4722366482869645213695"
+            ]
+        );
+    }
+
+    #[test]
+    fn float_and_byte_literals() {
+        let mut vm = VM::init_vm();
+        vm.interpret("print 2.0;\nprint 255u8;\nprint 0x0Au8;\n")
+            .unwrap();
+        let values: Vec<Value> = vm.test_values.drain(..).collect();
+        assert!(matches!(values[0], Value::Number(LNum::Float(f)) if f == 2.0));
+        assert!(matches!(values[1], Value::Number(LNum::Byte(255))));
+        assert!(matches!(values[2], Value::Number(LNum::Byte(10))));
+    }
+
+    #[test]
+    fn malformed_number_literals() {
+        let cases = [
+            ("0x;", "Expect digits after the base prefix.", "0x"),
+            ("0b102;", "Invalid digit in binary literal.", "2"),
+            ("1__0;", "Digit separator must be between digits.", "__0"),
+            ("1e+;", "Expect digits in exponent.", "e+"),
+            ("1.5u8;", "Byte literals must be integers.", "u8"),
+            (
+                "256u8;",
+                "Byte literal out of range, it must be between 0 and 255.",
+                "256u8",
+            ),
+        ];
+        for (literal, message, highlighted) in cases {
+            let code = format!("print {}\n", literal);
+            let mut vm = VM::init_vm();
+            let error = vm.interpret(&code).unwrap_err();
+            let diagnostic = &error.diagnostics[0];
+            assert_eq!(diagnostic.message, message);
+            assert_eq!(
+                &code[diagnostic.span.start..diagnostic.span.end],
+                highlighted
+            );
+        }
+    }
+
+    #[test]
+    fn string_escapes() {
+        let code: &str = "print \"a\\tb\\\"c\\\\d\\n\\u{1F600}\";\n";
+        assert_eq!(printed(code), vec!["a\tb\"c\\d\n\u{1F600}"]);
+    }
+
+    #[test]
+    fn raw_strings_skip_escapes() {
+        let code: &str = "print r\"C:\\new\\table\";\n";
+        assert_eq!(printed(code), vec!["C:\\new\\table"]);
+    }
+
+    #[test]
+    fn triple_quoted_strings_strip_indentation() {
+        let code: &str = "fun f() {\n    return \"\"\"\n        hello\n          \"world\"\n\n        end\\t!\n        \"\"\";\n}\nprint f();\nprint \"\"\"one line\"\"\";\n";
+        assert_eq!(
+            printed(code),
+            vec!["hello\n  \"world\"\n\nend\t!", "one line"]
+        );
+    }
+
+    #[test]
+    fn unknown_escape_points_at_column() {
+        let code: &str = "let a = \"\"\"\n  ok\n  bad \\q\n\"\"\";\n";
+        let mut vm = VM::init_vm();
+        let error = vm.interpret(code).unwrap_err();
+        let diagnostic = &error.diagnostics[0];
+        assert_eq!(diagnostic.message, "Unknown escape sequence '\\q'.");
+        assert_eq!((diagnostic.line, diagnostic.column), (3, 7));
+        assert_eq!(&code[diagnostic.span.start..diagnostic.span.end], "\\q");
+    }
+
+    #[test]
+    fn string_interpolation() {
+        let code: &str = "let name = \"Lumi\";\nlet age = 3;\nprint \"Hello ${name}, you are ${age + 1}\";\nprint \"${nil} ${true} ${[1, 2]}${\"!\"}\";\n";
+        assert_eq!(
+            printed(code),
+            vec!["Hello Lumi, you are 4", "nil true [1, 2]!"]
+        );
+    }
+
+    #[test]
+    fn nested_interpolation() {
+        let code: &str = "let a = 1;\nprint \"x ${\"y ${a + 1} z\"} w\";\n";
+        assert_eq!(printed(code), vec!["x y 2 z w"]);
+    }
+
+    #[test]
+    fn escaped_and_raw_interpolation() {
+        let code: &str = "print \"\\${a}\";\nprint r\"${a}\";\n";
+        assert_eq!(printed(code), vec!["${a}", "${a}"]);
+    }
+
+    #[test]
+    fn break_and_continue() {
+        let code: &str = "let i = 0;\nwhile (true) {\n  let x = i * 10;\n  i = i + 1;\n  if (i == 2) continue;\n  if (i > 3) break;\n  print x;\n}\nfor (let j = 0; j < 5; j = j + 1) {\n  let s = \"s\";\n  if (j == 1) continue;\n  if (j == 3) break;\n  print j;\n}\nprint i;\n";
+        assert_eq!(printed(code), vec!["0", "20", "0", "2", "4"]);
+    }
+
+    #[test]
+    fn labeled_break_and_continue() {
+        let code: &str = "outer: for (let a = 0; a < 3; a = a + 1) {\n  let k = 0;\n  while (k < 3) {\n    k = k + 1;\n    fun f() { return k; }\n    if (k == 2) continue outer;\n    if (a == 2) break outer;\n    print \"${a},${f()}\";\n  }\n}\n";
+        assert_eq!(printed(code), vec!["0,1", "1,1"]);
+    }
+
+    #[test]
+    fn break_and_continue_outside_loop() {
+        let mut vm = VM::init_vm();
+        let error = vm
+            .interpret("break;\nfun f() { while (true) { fun g() { continue; } } }\nwhile (true) { break nope; }\n")
+            .unwrap_err();
+        let codes: Vec<ErrorCode> = error.diagnostics.iter().map(|d| d.code).collect();
+        assert_eq!(
+            codes,
+            vec![
+                ErrorCode::OutsideLoop,
+                ErrorCode::OutsideLoop,
+                ErrorCode::UnknownLabel
+            ]
+        );
+    }
+
+    #[test]
+    fn match_literals_ranges_and_guards() {
+        let code: &str = "fun describe(x) {\n  match (x) {\n    0 => return \"zero\";\n    1, 2 => return \"small\";\n    3..10 if x != 7 => return \"medium\";\n    10..=20 => { let s = \"large\"; return s; }\n    \"a\" => return \"letter\";\n    nil => return \"nothing\";\n    _ => return \"other\";\n  }\n}\nprint describe(0);\nprint describe(2.0);\nprint describe(5);\nprint describe(7);\nprint describe(20);\nprint describe(\"a\");\nprint describe(nil);\nprint describe(\"b\");\n";
+        assert_eq!(
+            printed(code),
+            vec!["zero", "small", "medium", "other", "large", "letter", "nothing", "other"]
+        );
+    }
+
+    #[test]
+    fn match_dense_integers() {
+        let code: &str = "fun day(d) {\n  match (d) {\n    1 => return \"mon\";\n    2 => return \"tue\";\n    3, 4 => return \"mid\";\n    6 => return \"sat\";\n    _ => return \"?\";\n  }\n}\nfor (let i = 0; i < 8; i = i + 1) print day(i);\nprint day(2.0);\nprint day(\"x\");\n";
+        assert_eq!(
+            printed(code),
+            vec!["?", "mon", "tue", "mid", "mid", "?", "sat", "?", "tue", "?"]
+        );
+    }
+
+    #[test]
+    fn match_requires_default_arm_last() {
+        let mut vm = VM::init_vm();
+        let error = vm
+            .interpret("match (1) { 1 => print 1; }\nmatch (1) { _ => print 1; 2 => print 2; }\n")
+            .unwrap_err();
+        let lines: Vec<(ErrorCode, usize)> =
+            error.diagnostics.iter().map(|d| (d.code, d.line)).collect();
+        assert_eq!(
+            lines,
+            vec![(ErrorCode::InvalidMatch, 1), (ErrorCode::InvalidMatch, 2)]
+        );
+    }
+
+    #[test]
+    fn keyword_without_parse_rule_is_an_error() {
+        let mut vm = VM::init_vm();
+        let error = vm.interpret("print final;\n").unwrap_err();
+        assert_eq!(error.diagnostics[0].code, ErrorCode::ExpectedExpression);
+    }
+
+    #[test]
+    fn assigning_to_final_is_a_compile_error() {
+        let final_errors = |code: &str| -> Vec<usize> {
+            let mut vm = VM::init_vm();
+            let error = vm.interpret(code).unwrap_err();
+            error
+                .diagnostics
+                .iter()
+                .filter(|d| d.code == ErrorCode::AssignToFinal)
+                .map(|d| d.line)
+                .collect()
+        };
+        assert_eq!(
+            final_errors("let final a = 1;\n{ let b = 2; a = 2; }\n"),
+            vec![2]
+        );
+        assert_eq!(final_errors("{ let final a = 1;\na = 2; }\n"), vec![2]);
+        assert_eq!(
+            final_errors("{ let final a = 1;\nfun f() { a = 2; } }\n"),
+            vec![2]
+        );
+        assert_eq!(
+            final_errors("fun f() { a = 2; }\nlet final a = 1;\n"),
+            vec![1]
+        );
+        assert_eq!(final_errors("let final a = 1;\nlet a = 2;\n"), vec![2]);
+    }
+
+    #[test]
+    fn final_variables_can_be_shadowed() {
+        assert_eq!(
+            printed("let final a = 1;\n{ let a = 2; a = 3; print a; }\nprint a;\n"),
+            vec!["3", "1"]
+        );
+    }
+
+    #[test]
+    fn final_global_from_earlier_script_is_checked_at_runtime() {
+        let mut vm = VM::init_vm();
+        vm.interpret("let final a = 1;\n").unwrap();
+        assert_eq!(vm.interpret("a = 2;\n").unwrap_err().kind, ErrorKind::Final);
+        assert_eq!(
+            vm.interpret("let a = 2;\n").unwrap_err().kind,
+            ErrorKind::Final
+        );
+        vm.interpret("print a;\n").unwrap();
+        assert_eq!(vm.test_values.pop().unwrap().to_string(), "1");
+    }
+
+    #[test]
+    fn compound_assignment() {
+        let code: &str = "let a = 10;\na += 5;\na -= 3;\na *= 2;\na /= 4;\na %= 4;\nprint a;\n{ let s = \"a\"; s += \"b\"; print s; }\nprint a += 1;\n";
+        assert_eq!(printed(code), vec!["2", "ab", "3"]);
+    }
+
+    #[test]
+    fn increment_and_decrement() {
+        let code: &str = "let i = 1;\nprint i++;\nprint i;\nprint ++i;\nprint i--;\nprint --i;\nprint 10 - i++ * 2;\nprint i;\nfun count() { let n = 0; fun next() { return ++n; } return next; }\nlet next = count();\nnext();\nprint next();\n";
+        assert_eq!(printed(code), vec!["1", "2", "3", "3", "1", "8", "2", "2"]);
+    }
+
+    #[test]
+    fn compound_assignment_to_invalid_target() {
+        let mut vm = VM::init_vm();
+        let error = vm
+            .interpret("let a = 1;\nlet b = 2;\na * b += 1;\n(a)++;\nprint a * b++;\n")
+            .unwrap_err();
+        let errors: Vec<(ErrorCode, usize, usize)> = error
+            .diagnostics
+            .iter()
+            .map(|d| (d.code, d.line, d.column))
+            .collect();
+        assert_eq!(
+            errors,
+            vec![
+                (ErrorCode::InvalidAssignmentTarget, 3, 7),
+                (ErrorCode::InvalidAssignmentTarget, 4, 4)
+            ]
+        );
+    }
+
+    #[test]
+    fn double_minus_is_a_decrement() {
+        let mut vm = VM::init_vm();
+        let error = vm
+            .interpret("let a = 5;\nlet b = 2;\nprint a--b;\nprint 1--2;\n")
+            .unwrap_err();
+        let errors: Vec<(ErrorCode, usize, usize)> = error
+            .diagnostics
+            .iter()
+            .map(|d| (d.code, d.line, d.column))
+            .collect();
+        assert_eq!(
+            errors,
+            vec![
+                (ErrorCode::ExpectedToken, 3, 10),
+                (ErrorCode::InvalidAssignmentTarget, 4, 8)
+            ]
+        );
+
+        let code: &str = "let a = 5;\nlet b = 2;\nprint a - -b;\nprint 1 - -2;\nprint - -b;\n";
+        assert_eq!(printed(code), vec!["7", "3", "2"]);
+    }
+
+    #[test]
+    fn increment_respects_final() {
+        let mut vm = VM::init_vm();
+        let error = vm
+            .interpret("let final a = 1;\na += 1;\na++;\n{ let final b = 1;\n--b; }\n")
+            .unwrap_err();
+        let lines: Vec<(ErrorCode, usize)> =
+            error.diagnostics.iter().map(|d| (d.code, d.line)).collect();
+        assert_eq!(
+            lines,
+            vec![
+                (ErrorCode::AssignToFinal, 2),
+                (ErrorCode::AssignToFinal, 3),
+                (ErrorCode::AssignToFinal, 5)
+            ]
+        );
+        assert_eq!(
+            vm.interpret("let s = \"a\";\ns++;\n").unwrap_err().kind,
+            ErrorKind::Type
+        );
+    }
+
+    #[test]
+    fn conditional_expression() {
+        let code: &str = "print true ? 1 : 2;\nprint nil ? 1 : 2;\nlet n = 5;\nprint n < 0 ? \"negative\" : n == 0 ? \"zero\" : \"positive\";\nprint false or true ? \"a\" : \"b\";\nlet x = 0;\ntrue ? x = 1 : (x = 2);\nprint x;\n";
+        assert_eq!(printed(code), vec!["1", "2", "positive", "a", "1"]);
+    }
+
+    #[test]
+    fn nil_coalescing_and_elvis() {
+        let code: &str = "print nil ?? 1;\nprint false ?? 1;\nprint 0 ?? 1;\nprint nil ?: 1;\nprint false ?: 1;\nprint 2 ?: 1;\nprint nil ?? nil ?? 3;\nprint nil ?? false or true;\nprint nil ?? 1 ? \"yes\" : \"no\";\n";
+        assert_eq!(
+            printed(code),
+            vec!["1", "false", "0", "1", "1", "2", "3", "true", "yes"]
+        );
+    }
+
+    #[test]
+    fn conditional_short_circuits() {
+        let code: &str = "let calls = 0;\nfun f() { calls += 1; return calls; }\nprint true ? 1 : f();\nprint 1 ?? f();\nprint 1 ?: f();\nprint calls;\n";
+        assert_eq!(printed(code), vec!["1", "1", "1", "0"]);
+    }
+
+    #[test]
+    fn unicode_identifiers() {
+        let code: &str = "let größe = 2;\nlet 変数 = 3;\nlet _snake_case = 4;\nprint größe * 変数 + _snake_case;\n";
+        assert_eq!(printed(code), vec!["10"]);
+    }
+
+    #[test]
+    fn string_length_and_index_by_char() {
+        let code: &str = "let s = \"héllo 😀\";\nprint len(s);\nprint byte_len(s);\nprint s[1];\nprint s[-1];\nprint byte_at(s, 1);\n";
+        assert_eq!(printed(code), vec!["7", "11", "é", "😀", "195"]);
+    }
+
+    #[test]
+    fn string_index_out_of_range() {
+        let mut vm = VM::init_vm();
+        assert_eq!(
+            vm.interpret("print \"é\"[1];\n").unwrap_err().kind,
+            ErrorKind::IndexOutOfRange
+        );
+    }
+
+    #[test]
+    fn column_counts_characters() {
+        let mut vm = VM::init_vm();
+        let error = vm.interpret("let a = \"ü\" + €;\n").unwrap_err();
+        assert_eq!(error.diagnostics[0].column, 15);
+    }
+
+    #[test]
+    fn unfinished_interpolation() {
+        let mut vm = VM::init_vm();
+        let error = vm.interpret("print \"a ${1 + } b\";\n").unwrap_err();
+        assert_eq!(error.diagnostics[0].code, ErrorCode::ExpectedExpression);
+        assert_eq!(error.diagnostics[0].column, 16);
+    }
+
+    fn error_lines(code: &str) -> Vec<usize> {
+        let mut vm = VM::init_vm();
+        let error = vm.interpret(code).unwrap_err();
+        error.diagnostics.iter().map(|d| d.line).collect()
+    }
+
+    #[test]
+    fn reports_every_syntax_error() {
+        let code: &str = "let a = ;\nprint 1 +;\nlet b = 2;\nlet = 3;\nprint b;\n";
+        assert_eq!(error_lines(code), vec![1, 2, 4]);
+    }
+
+    #[test]
+    fn skips_tokens_that_cannot_start_a_statement() {
+        for code in ["let a = 1;\n)\n", "; !=", "1; -=", "; .."] {
+            assert_eq!(error_lines(code).len(), 1, "{:?}", code);
+        }
+        assert_eq!(error_lines("1;\n) ] else\nprint 2 +;\n"), vec![2, 3]);
+        assert_eq!(error_lines("print 1;\n}\n}\nprint +;\n"), vec![2, 4]);
+    }
+
+    #[test]
+    fn recovers_inside_functions_and_classes() {
+        let code: &str =
+            "fun f() {\n  let a = ;\n  print a;\n}\nclass C {\n  m() { return +; }\n}\nprint );\n";
+        assert_eq!(error_lines(code), vec![2, 6, 8]);
+    }
+
+    #[test]
+    fn recovers_at_closing_brace() {
+        let code: &str =
+            "fun f() { let a = 1 }\nprint f();\nif (true) { print }\nwhile (false) print 1;\n";
+        assert_eq!(error_lines(code), vec![1, 3]);
+    }
+
+    #[test]
+    fn recovers_inside_class_body() {
+        let code: &str = "class C {\n  1\n  m() {}\n}\nprint 2 +;\n";
+        assert_eq!(error_lines(code), vec![2, 5]);
+    }
+
+    #[test]
+    fn recovers_at_statement_keywords() {
+        let code: &str = "let a = 1 +\nlet b = 2;\nprint b\nfor (;;) {}\nreturn 1;\n";
+        assert_eq!(error_lines(code), vec![2, 4, 5]);
+    }
+
+    #[test]
+    fn runs_after_compile_error() {
+        let mut vm = VM::init_vm();
+        assert!(vm.interpret("let a = ;\n").is_err());
+        vm.interpret("print 1;\n").unwrap();
+        assert_eq!(
+            vm.test_values.pop().unwrap(),
+            Value::Number(LNum::Int(LInt::Small(1)))
+        );
+    }
 }
 
 //     #[test]
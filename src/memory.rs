@@ -0,0 +1,212 @@
+use std::mem::size_of;
+
+use crate::{
+    core::Table,
+    object::{Obj, ObjHeader, ObjRef, ObjString},
+    utils::hash_str,
+    value::{HashKey, Value},
+};
+
+// The heap grows this many times its live size before the next collection.
+const GC_HEAP_GROW_FACTOR: usize = 2;
+// The first collection runs once this many bytes have been allocated.
+const FIRST_GC: usize = 1024 * 1024;
+
+// Owns every object created by the compiler or the vm.
+// Objects are boxed so their address stays the same while the vector grows.
+//
+// Collection is a mark-and-sweep in two steps: whoever owns the roots (the vm or the compiler)
+// marks them first, collect then traces everything reachable from them and frees the rest.
+#[derive(Debug)]
+pub struct Heap {
+    #[allow(clippy::vec_box)]
+    objects: Vec<Box<ObjHeader>>,
+    // Every string ever created, so equal strings share a single object.
+    // It doesn't keep its strings alive, unreachable ones are removed before sweeping.
+    strings: Table,
+    // Marked objects whose references haven't been traced yet.
+    gray: Vec<ObjRef>,
+    bytes_allocated: usize,
+    next_gc: usize,
+}
+
+impl Heap {
+    pub fn new() -> Self {
+        Self {
+            objects: Vec::new(),
+            strings: Table::init(),
+            gray: Vec::new(),
+            bytes_allocated: 0,
+            next_gc: FIRST_GC,
+        }
+    }
+
+    // Allocating never collects by itself, callers check should_collect first since only
+    // they know the roots.
+    pub fn alloc(&mut self, obj: Obj) -> ObjRef {
+        let size = size_of_obj(&obj);
+        self.bytes_allocated += size;
+
+        let mut header = Box::new(ObjHeader {
+            is_marked: false,
+            size,
+            obj,
+        });
+        let obj_ref = ObjRef::new(&mut header);
+        self.objects.push(header);
+        obj_ref
+    }
+
+    // Returns the string object for chars, creating it only when it doesn't exist yet.
+    pub fn intern(&mut self, chars: &str) -> ObjRef {
+        let hash = hash_str(chars.as_bytes(), chars.len());
+        if let Some(interned) = self.strings.find_string(chars.as_bytes(), hash) {
+            return interned;
+        }
+
+        let string = self.alloc(Obj::String(ObjString::new(chars)));
+        self.strings.set(string, Value::Nil);
+        string
+    }
+
+    pub fn should_collect(&self) -> bool {
+        cfg!(feature = "gc_stress") || self.bytes_allocated > self.next_gc
+    }
+
+    #[cfg(test)]
+    pub fn bytes_allocated(&self) -> usize {
+        self.bytes_allocated
+    }
+
+    #[cfg(test)]
+    pub fn set_next_gc(&mut self, next_gc: usize) {
+        self.next_gc = next_gc;
+    }
+
+    pub fn mark_value(&mut self, value: &Value) {
+        if let Value::Object(obj) = value {
+            self.mark_object(*obj);
+        }
+    }
+
+    pub fn mark_object(&mut self, mut obj: ObjRef) {
+        if obj.is_marked() {
+            return;
+        }
+
+        obj.set_marked(true);
+        self.gray.push(obj);
+    }
+
+    pub fn mark_table(&mut self, table: &Table) {
+        for (key, value) in table.iter() {
+            self.mark_object(key);
+            self.mark_value(value);
+        }
+    }
+
+    // Marks everything obj refers to. Also used for objects that are about to be allocated,
+    // their references aren't reachable from anywhere else yet.
+    pub fn mark_references(&mut self, obj: &Obj) {
+        match obj {
+            Obj::String(_) | Obj::Native(_) => {}
+            Obj::Function(function) => {
+                for constant in &function.chunk.constants.values {
+                    self.mark_value(constant);
+                }
+            }
+            Obj::Closure(closure) => {
+                self.mark_object(closure.function);
+                for upvalue in &closure.upvalues {
+                    self.mark_object(*upvalue);
+                }
+            }
+            Obj::Upvalue(upvalue) => {
+                if let Some(closed) = &upvalue.closed {
+                    self.mark_value(closed);
+                }
+            }
+            Obj::Class(class) => self.mark_table(&class.methods),
+            Obj::Instance(instance) => {
+                self.mark_object(instance.class);
+                self.mark_table(&instance.fields);
+            }
+            Obj::BoundMethod(bound_method) => {
+                self.mark_value(&bound_method.receiver);
+                self.mark_object(bound_method.method);
+            }
+            Obj::List(list) => {
+                for item in &list.items {
+                    self.mark_value(item);
+                }
+            }
+            Obj::Map(map) => {
+                for (key, value) in map.iter() {
+                    self.mark_value(key);
+                    self.mark_value(value);
+                }
+            }
+        }
+    }
+
+    // Traces everything reachable from the marked roots and frees all other objects.
+    pub fn collect(&mut self) {
+        while let Some(obj) = self.gray.pop() {
+            self.mark_references(obj.as_obj());
+        }
+
+        self.strings.remove_unmarked();
+        self.sweep();
+        self.next_gc = self.bytes_allocated * GC_HEAP_GROW_FACTOR;
+    }
+
+    fn sweep(&mut self) {
+        let mut freed = 0;
+        self.objects.retain_mut(|header| {
+            if header.is_marked {
+                header.is_marked = false;
+                true
+            } else {
+                freed += header.size;
+                false
+            }
+        });
+        self.bytes_allocated -= freed;
+    }
+
+    // Lists and maps grow after they are allocated, so their size is measured again after
+    // anything is added to them.
+    pub fn resize(&mut self, mut obj: ObjRef) {
+        let size = size_of_obj(obj.as_obj());
+        self.bytes_allocated = self.bytes_allocated - obj.size() + size;
+        obj.set_size(size);
+    }
+
+    pub fn free(&mut self) {
+        self.strings.free();
+        self.gray.clear();
+        self.objects.clear();
+        self.bytes_allocated = 0;
+    }
+}
+
+// An estimate of the memory an object takes, including what it owns outside of its header.
+fn size_of_obj(obj: &Obj) -> usize {
+    let owned = match obj {
+        Obj::String(string) => string.as_bytes().len(),
+        Obj::Function(function) => {
+            function.chunk.code.len()
+                + function.chunk.lines.len() * size_of::<i32>()
+                + function.chunk.columns.len() * size_of::<usize>()
+                + function.chunk.constants.len() * size_of::<Value>()
+        }
+        Obj::Closure(closure) => closure.upvalues.len() * size_of::<ObjRef>(),
+        Obj::List(list) => list.items.capacity() * size_of::<Value>(),
+        Obj::Map(map) => {
+            map.capacity() * (size_of::<Option<(Value, Value)>>() + size_of::<(HashKey, usize)>())
+        }
+        _ => 0,
+    };
+
+    size_of::<ObjHeader>() + owned
+}
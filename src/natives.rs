@@ -0,0 +1,125 @@
+use crate::{
+    lnum::{LInt, LNum},
+    object::{Obj, ObjList},
+    value::Value,
+    vm::VM,
+};
+
+// Returns the number of items in a list or map, or the number of characters in a string.
+pub fn len(_vm: &mut VM, args: Vec<Value>) -> Result<Value, String> {
+    let length = match args[0].as_object() {
+        Some(Obj::List(list)) => list.items.len(),
+        Some(Obj::Map(map)) => map.len(),
+        Some(Obj::String(string)) => string.char_count(),
+        _ => return Err("len() expects a list, a map or a string.".to_string()),
+    };
+
+    Ok(Value::Number(LNum::Int(LInt::new(length as i64))))
+}
+
+// Returns the number of bytes in the UTF-8 encoding of a string.
+pub fn byte_len(_vm: &mut VM, args: Vec<Value>) -> Result<Value, String> {
+    match args[0].as_string_obj() {
+        Some(string) => Ok(Value::Number(LNum::Int(
+            LInt::new(string.byte_len() as i64),
+        ))),
+        None => Err("byte_len() expects a string.".to_string()),
+    }
+}
+
+// Returns the byte at an index in the UTF-8 encoding of a string, negative indexes
+// count back from the end.
+pub fn byte_at(_vm: &mut VM, args: Vec<Value>) -> Result<Value, String> {
+    let Some(string) = args[0].as_string_obj() else {
+        return Err("byte_at() expects a string as its first argument.".to_string());
+    };
+    let Some(index) = args[1].as_int() else {
+        return Err("byte_at() expects an integer index.".to_string());
+    };
+    match string.byte_at(index) {
+        Some(byte) => Ok(Value::Number(LNum::Byte(byte))),
+        None => Err(format!(
+            "Byte index {} out of range for string of {} bytes.",
+            index,
+            string.byte_len()
+        )),
+    }
+}
+
+// Appends a value to the end of a list.
+pub fn push(vm: &mut VM, args: Vec<Value>) -> Result<Value, String> {
+    match &args[0] {
+        Value::Object(obj) if matches!(obj.as_obj(), Obj::List(_)) => {
+            let mut list = *obj;
+            if let Obj::List(list) = list.as_obj_mut() {
+                list.items.push(args[1].clone());
+            }
+            vm.resize_object(*obj);
+            Ok(Value::Nil)
+        }
+        _ => Err("push() expects a list as its first argument.".to_string()),
+    }
+}
+
+// Removes the last value of a list and returns it.
+pub fn pop(_vm: &mut VM, args: Vec<Value>) -> Result<Value, String> {
+    match &args[0] {
+        Value::Object(obj) if matches!(obj.as_obj(), Obj::List(_)) => {
+            let mut list = *obj;
+            match list.as_obj_mut() {
+                Obj::List(list) => list
+                    .items
+                    .pop()
+                    .ok_or_else(|| "Can't pop from an empty list.".to_string()),
+                _ => unreachable!(),
+            }
+        }
+        _ => Err("pop() expects a list.".to_string()),
+    }
+}
+
+// Returns a new list holding the keys of a map, in insertion order.
+pub fn keys(vm: &mut VM, args: Vec<Value>) -> Result<Value, String> {
+    let keys = match args[0].as_map() {
+        Some(map) => map.iter().map(|(key, _)| key.clone()).collect(),
+        None => return Err("keys() expects a map.".to_string()),
+    };
+
+    let list = vm.alloc(Obj::List(ObjList::new(keys)));
+    Ok(Value::Object(list))
+}
+
+// Returns a new list holding the values of a map, in insertion order.
+pub fn values(vm: &mut VM, args: Vec<Value>) -> Result<Value, String> {
+    let values = match args[0].as_map() {
+        Some(map) => map.iter().map(|(_, value)| value.clone()).collect(),
+        None => return Err("values() expects a map.".to_string()),
+    };
+
+    let list = vm.alloc(Obj::List(ObjList::new(values)));
+    Ok(Value::Object(list))
+}
+
+// Checks whether a map has an entry for the given key.
+pub fn has(_vm: &mut VM, args: Vec<Value>) -> Result<Value, String> {
+    let key = VM::map_key(&args[1])?;
+    match args[0].as_map() {
+        Some(map) => Ok(Value::Bool(map.contains(&key))),
+        None => Err("has() expects a map as its first argument.".to_string()),
+    }
+}
+
+// Deletes the entry for a key, returns whether the key was present.
+pub fn remove(_vm: &mut VM, args: Vec<Value>) -> Result<Value, String> {
+    let key = VM::map_key(&args[1])?;
+    match &args[0] {
+        Value::Object(obj) if matches!(obj.as_obj(), Obj::Map(_)) => {
+            let mut map = *obj;
+            match map.as_obj_mut() {
+                Obj::Map(map) => Ok(Value::Bool(map.delete(&key))),
+                _ => unreachable!(),
+            }
+        }
+        _ => Err("remove() expects a map as its first argument.".to_string()),
+    }
+}
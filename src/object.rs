@@ -1,52 +1,224 @@
+use std::{collections::HashMap, ptr::NonNull};
+
 use crate::{
     chunk::{Chunk, ChunkWrite},
+    core::Table,
     utils::hash_str,
+    value::{HashKey, Value},
+    vm::VM,
 };
 
 #[derive(Debug, Clone, PartialEq)]
 pub enum ObjType {
     String,
+    Function,
+    Closure,
+    Upvalue,
+    Class,
+    Instance,
+    BoundMethod,
+    Native,
+    List,
+    Map,
 }
 
 #[derive(Debug, Clone, PartialEq)]
 pub enum Obj {
     String(ObjString),
     Function(ObjFunction),
+    Closure(ObjClosure),
+    Upvalue(ObjUpvalue),
+    Class(ObjClass),
+    Instance(ObjInstance),
+    BoundMethod(ObjBoundMethod),
+    Native(ObjNative),
+    List(ObjList),
+    Map(ObjMap),
+}
+
+// Every object on the heap is wrapped in a header holding the bookkeeping of the garbage collector.
+#[derive(Debug)]
+pub struct ObjHeader {
+    pub is_marked: bool,
+    // The number of bytes accounted for this object, updated when a list or map grows.
+    pub size: usize,
+    pub obj: Obj,
 }
 
+// A handle to an object living on the heap. Copying the handle never copies the object,
+// so every value referring to the same object sees the same data.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ObjRef(NonNull<ObjHeader>);
+
 #[derive(Debug, Clone, PartialEq)]
+// Always valid UTF-8, which the &str it's made from guarantees, so reading it can't fail.
 pub struct ObjString {
-    length: usize,
-    chars: Vec<u8>,
+    chars: String,
+    // Counted once here, strings are immutable.
+    char_count: usize,
     pub hash: u32,
 }
 
 #[derive(Debug, Clone, PartialEq)]
 pub struct ObjFunction {
-    arity: usize,
-    chunk: Chunk,
-    name: Option<ObjString>,
+    pub arity: usize,
+    pub upvalue_count: usize,
+    pub chunk: Chunk,
+    pub name: Option<ObjString>,
 }
 
-impl ObjString {
-    pub fn new(bytes: &[u8], length: usize) -> Self {
-        let chars = &bytes[..length];
+// A function together with the variables it captured from its enclosing functions.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ObjClosure {
+    pub function: ObjRef,
+    pub upvalues: Vec<ObjRef>,
+}
+
+// A captured variable. While open it refers to a stack slot through location,
+// once the slot goes out of scope the value is moved into closed.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ObjUpvalue {
+    pub location: usize,
+    pub closed: Option<Value>,
+}
 
+#[derive(Debug, Clone, PartialEq)]
+pub struct ObjClass {
+    pub name: ObjString,
+    pub methods: Table,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ObjInstance {
+    pub class: ObjRef,
+    pub fields: Table,
+}
+
+pub type NativeFn = fn(&mut VM, Vec<Value>) -> Result<Value, String>;
+
+// A function implemented in Rust that scripts can call like any other function.
+#[derive(Debug, Clone)]
+pub struct ObjNative {
+    pub name: ObjString,
+    pub arity: usize,
+    pub function: NativeFn,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ObjList {
+    pub items: Vec<Value>,
+}
+
+// Entries keep the original key value so it can be handed back by keys(), and stay in
+// insertion order. The index maps each hashed key to its entry.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ObjMap {
+    // None marks a deleted entry, so deleting doesn't shift the entries after it.
+    entries: Vec<Option<(Value, Value)>>,
+    index: HashMap<HashKey, usize>,
+}
+
+// A method that has been looked up on an instance, so calling it later still knows what 'this' is.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ObjBoundMethod {
+    pub receiver: Value,
+    pub method: ObjRef,
+}
+
+impl Obj {
+    pub fn obj_type(&self) -> ObjType {
+        match self {
+            Obj::String(_) => ObjType::String,
+            Obj::Function(_) => ObjType::Function,
+            Obj::Closure(_) => ObjType::Closure,
+            Obj::Upvalue(_) => ObjType::Upvalue,
+            Obj::Class(_) => ObjType::Class,
+            Obj::Instance(_) => ObjType::Instance,
+            Obj::BoundMethod(_) => ObjType::BoundMethod,
+            Obj::Native(_) => ObjType::Native,
+            Obj::List(_) => ObjType::List,
+            Obj::Map(_) => ObjType::Map,
+        }
+    }
+}
+
+impl ObjRef {
+    pub fn new(header: &mut ObjHeader) -> Self {
+        Self(NonNull::from(header))
+    }
+
+    pub fn as_obj(&self) -> &Obj {
+        // The heap keeps every object alive for as long as the garbage collector can reach it,
+        // so the pointer stays valid for as long as a value can hold it.
+        unsafe { &self.0.as_ref().obj }
+    }
+
+    pub fn as_obj_mut(&mut self) -> &mut Obj {
+        unsafe { &mut self.0.as_mut().obj }
+    }
+
+    pub fn is_marked(&self) -> bool {
+        unsafe { self.0.as_ref().is_marked }
+    }
+
+    pub fn size(&self) -> usize {
+        unsafe { self.0.as_ref().size }
+    }
+
+    pub fn set_size(&mut self, size: usize) {
+        unsafe { self.0.as_mut().size = size }
+    }
+
+    pub fn set_marked(&mut self, is_marked: bool) {
+        unsafe { self.0.as_mut().is_marked = is_marked }
+    }
+}
+
+impl ObjString {
+    pub fn new(chars: &str) -> Self {
         Self {
-            length,
-            chars: chars.to_vec(),
-            hash: hash_str(chars, length),
+            chars: chars.to_string(),
+            char_count: chars.chars().count(),
+            hash: hash_str(chars.as_bytes(), chars.len()),
         }
     }
 
+    pub fn as_bytes(&self) -> &[u8] {
+        self.chars.as_bytes()
+    }
+
     pub fn as_str(&self) -> &str {
-        std::str::from_utf8(&self.chars).expect("Expecting a valid UTF-8 representation.")
+        &self.chars
+    }
+
+    // The length in characters, which is what len() returns.
+    pub fn char_count(&self) -> usize {
+        self.char_count
+    }
+
+    pub fn byte_len(&self) -> usize {
+        self.chars.len()
+    }
+
+    // The character at a possibly negative index, counting back from the end of the string.
+    pub fn char_at(&self, index: i64) -> Option<char> {
+        let index = resolve_index(index, self.char_count)?;
+        if self.char_count == self.chars.len() {
+            // ASCII only, so characters and bytes line up.
+            return Some(self.chars.as_bytes()[index] as char);
+        }
+        self.chars.chars().nth(index)
+    }
+
+    pub fn byte_at(&self, index: i64) -> Option<u8> {
+        let index = resolve_index(index, self.chars.len())?;
+        Some(self.chars.as_bytes()[index])
     }
+}
 
-    pub fn to_string(&self) -> String {
-        std::str::from_utf8(&self.chars)
-            .expect("Expecting a valid UTF-8 representation.")
-            .to_string()
+impl std::fmt::Display for ObjString {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.as_str())
     }
 }
 
@@ -54,8 +226,278 @@ impl ObjFunction {
     pub fn new() -> Self {
         Self {
             arity: 0,
+            upvalue_count: 0,
             chunk: Chunk::new(),
             name: None,
         }
     }
 }
+
+impl std::fmt::Display for ObjFunction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.name {
+            Some(name) => write!(f, "<fn {}>", name),
+            None => write!(f, "<script>"),
+        }
+    }
+}
+
+impl ObjClosure {
+    pub fn new(function: ObjRef, upvalues: Vec<ObjRef>) -> Self {
+        Self { function, upvalues }
+    }
+}
+
+impl ObjUpvalue {
+    pub fn new(location: usize) -> Self {
+        Self {
+            location,
+            closed: None,
+        }
+    }
+}
+
+impl ObjClass {
+    pub fn new(name: ObjString) -> Self {
+        Self {
+            name,
+            methods: Table::init(),
+        }
+    }
+}
+
+impl ObjInstance {
+    pub fn new(class: ObjRef) -> Self {
+        Self {
+            class,
+            fields: Table::init(),
+        }
+    }
+
+    pub fn class_obj(&self) -> &ObjClass {
+        match self.class.as_obj() {
+            Obj::Class(class) => class,
+            _ => unreachable!("An instance always belongs to a class."),
+        }
+    }
+}
+
+impl ObjBoundMethod {
+    pub fn new(receiver: Value, method: ObjRef) -> Self {
+        Self { receiver, method }
+    }
+}
+
+impl ObjNative {
+    pub fn new(name: &str, arity: usize, function: NativeFn) -> Self {
+        Self {
+            name: ObjString::new(name),
+            arity,
+            function,
+        }
+    }
+}
+
+// Natives are registered once per name, comparing function pointers is not reliable.
+impl PartialEq for ObjNative {
+    fn eq(&self, other: &Self) -> bool {
+        self.name == other.name && self.arity == other.arity
+    }
+}
+
+impl ObjList {
+    pub fn new(items: Vec<Value>) -> Self {
+        Self { items }
+    }
+
+    // Resolves a possibly negative index, counting back from the end of the list.
+    pub fn index(&self, index: i64) -> Option<usize> {
+        resolve_index(index, self.items.len())
+    }
+}
+
+impl std::fmt::Display for ObjList {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.write(f, &mut Vec::new())
+    }
+}
+
+impl ObjList {
+    fn write(
+        &self,
+        f: &mut std::fmt::Formatter<'_>,
+        visiting: &mut Vec<*const ()>,
+    ) -> std::fmt::Result {
+        visiting.push(self as *const Self as *const ());
+        write!(f, "[")?;
+        for (i, item) in self.items.iter().enumerate() {
+            if i > 0 {
+                write!(f, ", ")?;
+            }
+            write_nested(f, item, visiting)?;
+        }
+        visiting.pop();
+        write!(f, "]")
+    }
+}
+
+impl ObjMap {
+    pub fn new() -> Self {
+        Self {
+            entries: Vec::new(),
+            index: HashMap::new(),
+        }
+    }
+
+    pub fn get(&self, key: &HashKey) -> Option<&Value> {
+        self.index
+            .get(key)
+            .and_then(|&i| self.entries[i].as_ref())
+            .map(|(_, value)| value)
+    }
+
+    pub fn len(&self) -> usize {
+        self.index.len()
+    }
+
+    // The entries in insertion order.
+    pub fn iter(&self) -> impl Iterator<Item = &(Value, Value)> {
+        self.entries.iter().flatten()
+    }
+
+    // The number of entries there is room for, for estimating the memory the map takes.
+    pub fn capacity(&self) -> usize {
+        self.entries.capacity().max(self.index.capacity())
+    }
+
+    pub fn contains(&self, key: &HashKey) -> bool {
+        self.index.contains_key(key)
+    }
+
+    pub fn set(&mut self, hash_key: HashKey, key: Value, value: Value) {
+        match self.index.get(&hash_key) {
+            Some(&i) => {
+                if let Some(entry) = &mut self.entries[i] {
+                    entry.1 = value;
+                }
+            }
+            None => {
+                self.index.insert(hash_key, self.entries.len());
+                self.entries.push(Some((key, value)));
+            }
+        }
+    }
+
+    // Removes the entry for key, returns false when the key wasn't there.
+    pub fn delete(&mut self, key: &HashKey) -> bool {
+        let Some(removed) = self.index.remove(key) else {
+            return false;
+        };
+
+        self.entries[removed] = None;
+        if self.entries.len() > 2 * self.index.len() {
+            self.compact();
+        }
+        true
+    }
+
+    // Drops the deleted entries once they outnumber the live ones, so deleting stays
+    // constant time on average.
+    fn compact(&mut self) {
+        let mut moved_to = Vec::with_capacity(self.entries.len());
+        let mut live = 0;
+        for entry in &self.entries {
+            moved_to.push(live);
+            if entry.is_some() {
+                live += 1;
+            }
+        }
+        for i in self.index.values_mut() {
+            *i = moved_to[*i];
+        }
+        self.entries.retain(Option::is_some);
+    }
+}
+
+impl std::fmt::Display for ObjMap {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.write(f, &mut Vec::new())
+    }
+}
+
+impl ObjMap {
+    fn write(
+        &self,
+        f: &mut std::fmt::Formatter<'_>,
+        visiting: &mut Vec<*const ()>,
+    ) -> std::fmt::Result {
+        visiting.push(self as *const Self as *const ());
+        write!(f, "{{")?;
+        for (i, (key, value)) in self.iter().enumerate() {
+            if i > 0 {
+                write!(f, ", ")?;
+            }
+            write_nested(f, key, visiting)?;
+            write!(f, ": ")?;
+            write_nested(f, value, visiting)?;
+        }
+        visiting.pop();
+        write!(f, "}}")
+    }
+}
+
+// Writes a value inside a list or map. Strings are quoted so "1" and 1 can be told apart,
+// and a list or map that is already being written is shown as [...] or {...}.
+fn write_nested(
+    f: &mut std::fmt::Formatter<'_>,
+    value: &Value,
+    visiting: &mut Vec<*const ()>,
+) -> std::fmt::Result {
+    let Value::Object(obj) = value else {
+        return write!(f, "{}", value);
+    };
+    match obj.as_obj() {
+        Obj::String(string) => write!(f, "\"{}\"", string.as_str().escape_debug()),
+        Obj::List(list) if visiting.contains(&(list as *const ObjList as *const ())) => {
+            write!(f, "[...]")
+        }
+        Obj::List(list) => list.write(f, visiting),
+        Obj::Map(map) if visiting.contains(&(map as *const ObjMap as *const ())) => {
+            write!(f, "{{...}}")
+        }
+        Obj::Map(map) => map.write(f, visiting),
+        other => write!(f, "{}", other),
+    }
+}
+
+impl std::fmt::Display for Obj {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Obj::String(obj_string) => write!(f, "{}", obj_string),
+            Obj::Function(obj_function) => write!(f, "{}", obj_function),
+            Obj::Closure(obj_closure) => write!(f, "{}", obj_closure.function.as_obj()),
+            Obj::Upvalue(_) => write!(f, "upvalue"),
+            Obj::Class(obj_class) => write!(f, "{}", obj_class.name),
+            Obj::Instance(obj_instance) => {
+                write!(f, "{} instance", obj_instance.class_obj().name)
+            }
+            Obj::BoundMethod(obj_bound_method) => {
+                write!(f, "{}", obj_bound_method.method.as_obj())
+            }
+            Obj::Native(obj_native) => write!(f, "<native fn {}>", obj_native.name),
+            Obj::List(obj_list) => write!(f, "{}", obj_list),
+            Obj::Map(obj_map) => write!(f, "{}", obj_map),
+        }
+    }
+}
+
+// Negative indexes count back from the end, None when the index is out of range.
+fn resolve_index(index: i64, len: usize) -> Option<usize> {
+    let len = len as i64;
+    let index = if index < 0 { len + index } else { index };
+    if index >= 0 && index < len {
+        Some(index as usize)
+    } else {
+        None
+    }
+}
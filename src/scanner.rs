@@ -1,18 +1,41 @@
-use std::{collections::HashMap, fmt};
+use std::fmt;
 
-#[derive(Debug)]
+use unicode_ident::{is_xid_continue, is_xid_start};
+
+#[derive(Debug, Clone)]
 pub struct Scanner<'a> {
     pub start: &'a [u8],
     pub current: &'a [u8],
     pub line: usize,
+    // The scanner always moves a whole character at a time, so the rest of the source
+    // can be read as a str to decode its next code point.
+    source: &'a str,
+    // Byte offset of the first character on the current line.
+    line_start: usize,
+    // Where the token being scanned starts, tokens like strings can span multiple lines.
+    start_line: usize,
+    start_column: usize,
+    // One entry for every string interpolation we're inside of, counting the braces opened in it,
+    // so we know which '}' ends the interpolation and continues the string.
+    interpolations: Vec<usize>,
+}
+
+// Byte offsets into the source, start is inclusive and end exclusive.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
 }
 
+// For error tokens start holds the error message, span still points at the offending source.
 #[derive(Debug, Clone, PartialEq)]
 pub struct Token<'a> {
     pub token_type: TokenType,
     pub start: &'a [u8],
     pub length: usize,
     pub line: usize,
+    pub column: usize,
+    pub span: Span,
 }
 
 impl<'a> Token<'a> {
@@ -22,6 +45,8 @@ impl<'a> Token<'a> {
             start: &[],
             length: 0,
             line: 0,
+            column: 0,
+            span: Span::default(),
         }
     }
 }
@@ -35,30 +60,58 @@ pub enum TokenType {
     LeftBracket,
     RightBracket,
     Comma,
+    Colon,
     Dot,
+    DotDot,
+    DotDotEqual,
     Minus,
+    MinusEqual,
+    MinusMinus,
     Plus,
+    PlusEqual,
+    PlusPlus,
     Semicolon,
     Slash,
+    SlashEqual,
     Star,
+    StarEqual,
+    StarStar,
+    Percent,
+    PercentEqual,
+    Question,
+    QuestionColon,
+    QuestionQuestion,
+    Tilde,
+    TildeSlash,
+    Ampersand,
+    Pipe,
+    Caret,
     Bang,
     BangEqual,
     Equal,
     EqualEqual,
+    FatArrow,
     Greater,
     GreaterEqual,
     Less,
     LessEqual,
+    LessLess,
+    GreaterGreater,
     Identifier,
     String,
+    // The part of a string up to and including the '${' of an embedded expression.
+    Interpolation,
     Number,
     And,
+    Break,
     Class,
+    Continue,
     Else,
     False,
     For,
     Fun,
     If,
+    Match,
     Nil,
     Or,
     Print,
@@ -73,6 +126,11 @@ pub enum TokenType {
     Eof,
 }
 
+impl TokenType {
+    // The number of token types, Eof has to stay the last one.
+    pub const COUNT: usize = TokenType::Eof as usize + 1;
+}
+
 impl fmt::Display for TokenType {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
@@ -83,30 +141,57 @@ impl fmt::Display for TokenType {
             TokenType::LeftBracket => write!(f, "LeftBracket"),
             TokenType::RightBracket => write!(f, "RightBracket"),
             TokenType::Comma => write!(f, "Comma"),
+            TokenType::Colon => write!(f, "Colon"),
             TokenType::Dot => write!(f, "Dot"),
+            TokenType::DotDot => write!(f, "DotDot"),
+            TokenType::DotDotEqual => write!(f, "DotDotEqual"),
             TokenType::Minus => write!(f, "Minus"),
+            TokenType::MinusEqual => write!(f, "MinusEqual"),
+            TokenType::MinusMinus => write!(f, "MinusMinus"),
             TokenType::Plus => write!(f, "Plus"),
+            TokenType::PlusEqual => write!(f, "PlusEqual"),
+            TokenType::PlusPlus => write!(f, "PlusPlus"),
             TokenType::Semicolon => write!(f, "Semicolon"),
             TokenType::Slash => write!(f, "Slash"),
+            TokenType::SlashEqual => write!(f, "SlashEqual"),
             TokenType::Star => write!(f, "Star"),
+            TokenType::StarEqual => write!(f, "StarEqual"),
+            TokenType::StarStar => write!(f, "StarStar"),
+            TokenType::Percent => write!(f, "Percent"),
+            TokenType::PercentEqual => write!(f, "PercentEqual"),
+            TokenType::Question => write!(f, "Question"),
+            TokenType::QuestionColon => write!(f, "QuestionColon"),
+            TokenType::QuestionQuestion => write!(f, "QuestionQuestion"),
+            TokenType::Tilde => write!(f, "Tilde"),
+            TokenType::TildeSlash => write!(f, "TildeSlash"),
+            TokenType::Ampersand => write!(f, "Ampersand"),
+            TokenType::Pipe => write!(f, "Pipe"),
+            TokenType::Caret => write!(f, "Caret"),
             TokenType::Bang => write!(f, "Bang"),
             TokenType::BangEqual => write!(f, "BangEqual"),
             TokenType::Equal => write!(f, "Equal"),
             TokenType::EqualEqual => write!(f, "EqualEqual"),
+            TokenType::FatArrow => write!(f, "FatArrow"),
             TokenType::Greater => write!(f, "Greater"),
             TokenType::GreaterEqual => write!(f, "GreaterEqual"),
             TokenType::Less => write!(f, "Less"),
             TokenType::LessEqual => write!(f, "LessEqual"),
+            TokenType::LessLess => write!(f, "LessLess"),
+            TokenType::GreaterGreater => write!(f, "GreaterGreater"),
             TokenType::Identifier => write!(f, "Identifier"),
             TokenType::String => write!(f, "String"),
+            TokenType::Interpolation => write!(f, "Interpolation"),
             TokenType::Number => write!(f, "Number"),
             TokenType::And => write!(f, "And"),
+            TokenType::Break => write!(f, "Break"),
             TokenType::Class => write!(f, "Class"),
+            TokenType::Continue => write!(f, "Continue"),
             TokenType::Else => write!(f, "Else"),
             TokenType::False => write!(f, "False"),
             TokenType::For => write!(f, "For"),
             TokenType::Fun => write!(f, "Fun"),
             TokenType::If => write!(f, "If"),
+            TokenType::Match => write!(f, "Match"),
             TokenType::Nil => write!(f, "Nil"),
             TokenType::Or => write!(f, "Or"),
             TokenType::Print => write!(f, "Print"),
@@ -129,20 +214,32 @@ impl<'a> Scanner<'a> {
             start: &[],
             current: &[],
             line: 0,
+            source: "",
+            line_start: 0,
+            start_line: 0,
+            start_column: 0,
+            interpolations: Vec::new(),
         }
     }
 
-    pub fn init_scanner(source: &'a [u8]) -> Self {
+    pub fn init_scanner(source: &'a str) -> Self {
         Self {
-            start: source,
-            current: source,
+            start: source.as_bytes(),
+            current: source.as_bytes(),
             line: 1,
+            source,
+            line_start: 0,
+            start_line: 0,
+            start_column: 0,
+            interpolations: Vec::new(),
         }
     }
 
     pub fn scan_token(&mut self) -> Token<'a> {
         self.skip_whitespace();
         self.start = self.current;
+        self.start_line = self.line;
+        self.start_column = self.column_of(self.start);
 
         if self.is_at_end() {
             return self.make_token(TokenType::Eof);
@@ -150,61 +247,159 @@ impl<'a> Scanner<'a> {
 
         let c = self.advance();
 
-        if c.is_alphabetic() {
-            return self.identifier(c);
+        if c == 'r' && self.peek() == '"' {
+            self.advance();
+            return self.string(true);
         }
-        if c.is_digit(10) {
-            return self.number();
+        if c == '_' || is_xid_start(c) {
+            return self.identifier();
         }
-        return match c {
+        if c.is_ascii_digit() {
+            return self.number(c);
+        }
+        match c {
             '(' => self.make_token(TokenType::LeftParen),
             ')' => self.make_token(TokenType::RightParen),
-            '{' => self.make_token(TokenType::LeftBrace),
-            '}' => self.make_token(TokenType::RightBrace),
+            '{' => {
+                if let Some(braces) = self.interpolations.last_mut() {
+                    *braces += 1;
+                }
+                self.make_token(TokenType::LeftBrace)
+            }
+            '}' => match self.interpolations.last_mut() {
+                Some(0) => {
+                    self.interpolations.pop();
+                    self.string_contents(false, false)
+                }
+                Some(braces) => {
+                    *braces -= 1;
+                    self.make_token(TokenType::RightBrace)
+                }
+                None => self.make_token(TokenType::RightBrace),
+            },
             '[' => self.make_token(TokenType::LeftBracket),
             ']' => self.make_token(TokenType::RightBracket),
             ',' => self.make_token(TokenType::Comma),
-            '.' => self.make_token(TokenType::Dot),
+            ':' => self.make_token(TokenType::Colon),
+            '.' => {
+                if self.match_next('.') {
+                    if self.match_next('=') {
+                        self.make_token(TokenType::DotDotEqual)
+                    } else {
+                        self.make_token(TokenType::DotDot)
+                    }
+                } else {
+                    self.make_token(TokenType::Dot)
+                }
+            }
             ';' => self.make_token(TokenType::Semicolon),
-            '+' => self.make_token(TokenType::Plus),
-            '*' => self.make_token(TokenType::Star),
-            '/' => self.make_token(TokenType::Slash),
-            '-' => self.make_token(TokenType::Minus),
+            '+' => {
+                if self.match_next('+') {
+                    self.make_token(TokenType::PlusPlus)
+                } else if self.match_next('=') {
+                    self.make_token(TokenType::PlusEqual)
+                } else {
+                    self.make_token(TokenType::Plus)
+                }
+            }
+            '*' => {
+                if self.match_next('*') {
+                    self.make_token(TokenType::StarStar)
+                } else if self.match_next('=') {
+                    self.make_token(TokenType::StarEqual)
+                } else {
+                    self.make_token(TokenType::Star)
+                }
+            }
+            '/' => {
+                if self.match_next('=') {
+                    self.make_token(TokenType::SlashEqual)
+                } else {
+                    self.make_token(TokenType::Slash)
+                }
+            }
+            '%' => {
+                if self.match_next('=') {
+                    self.make_token(TokenType::PercentEqual)
+                } else {
+                    self.make_token(TokenType::Percent)
+                }
+            }
+            // '//' starts a comment, so integer division is written as '~/'.
+            '~' => {
+                if self.match_next('/') {
+                    self.make_token(TokenType::TildeSlash)
+                } else {
+                    self.make_token(TokenType::Tilde)
+                }
+            }
+            '?' => {
+                if self.match_next('?') {
+                    self.make_token(TokenType::QuestionQuestion)
+                } else if self.match_next(':') {
+                    self.make_token(TokenType::QuestionColon)
+                } else {
+                    self.make_token(TokenType::Question)
+                }
+            }
+            '&' => self.make_token(TokenType::Ampersand),
+            '|' => self.make_token(TokenType::Pipe),
+            '^' => self.make_token(TokenType::Caret),
+            '-' => {
+                if self.match_next('-') {
+                    self.make_token(TokenType::MinusMinus)
+                } else if self.match_next('=') {
+                    self.make_token(TokenType::MinusEqual)
+                } else {
+                    self.make_token(TokenType::Minus)
+                }
+            }
             '!' => {
                 if self.match_next('=') {
-                    return self.make_token(TokenType::BangEqual);
+                    self.make_token(TokenType::BangEqual)
                 } else {
-                    return self.make_token(TokenType::Bang);
+                    self.make_token(TokenType::Bang)
                 }
             }
             '=' => {
                 if self.match_next('=') {
-                    return self.make_token(TokenType::EqualEqual);
+                    self.make_token(TokenType::EqualEqual)
+                } else if self.match_next('>') {
+                    self.make_token(TokenType::FatArrow)
                 } else {
-                    return self.make_token(TokenType::Equal);
+                    self.make_token(TokenType::Equal)
                 }
             }
             '<' => {
                 if self.match_next('=') {
-                    return self.make_token(TokenType::LessEqual);
+                    self.make_token(TokenType::LessEqual)
+                } else if self.match_next('<') {
+                    self.make_token(TokenType::LessLess)
                 } else {
-                    return self.make_token(TokenType::Less);
+                    self.make_token(TokenType::Less)
                 }
             }
             '>' => {
                 if self.match_next('=') {
-                    return self.make_token(TokenType::GreaterEqual);
+                    self.make_token(TokenType::GreaterEqual)
+                } else if self.match_next('>') {
+                    self.make_token(TokenType::GreaterGreater)
                 } else {
-                    return self.make_token(TokenType::Greater);
+                    self.make_token(TokenType::Greater)
                 }
             }
-            '"' => return self.string(),
-            _ => self.error_token("Unexpected character.".to_string()),
-        };
+            '"' => self.string(false),
+            _ => self.error_token("Unexpected character."),
+        }
+    }
+
+    // The type of the token after the current one, without moving past it.
+    pub fn peek_token_type(&self) -> TokenType {
+        self.clone().scan_token().token_type
     }
 
     fn is_at_end(&mut self) -> bool {
-        self.current.len() == 0 || self.current[0] == b'\0'
+        self.current.is_empty() || self.current[0] == b'\0'
     }
 
     fn make_token(&self, token_type: TokenType) -> Token<'a> {
@@ -212,50 +407,92 @@ impl<'a> Scanner<'a> {
             token_type,
             start: self.start,
             length: self.start.len() - self.current.len(),
-            line: self.line,
+            line: self.start_line,
+            column: self.start_column,
+            span: self.span(),
         }
     }
 
-    fn error_token(&self, message: String) -> Token<'a> {
+    fn error_token(&self, message: &'static str) -> Token<'a> {
         Token {
             token_type: TokenType::Error,
-            start: self.current,
+            start: message.as_bytes(),
+            length: message.len(),
+            line: self.start_line,
+            column: self.start_column,
+            span: self.span(),
+        }
+    }
+
+    // An error pointing at part of the token, from the offending character to the end of the
+    // literal it's in. The rest of the literal is skipped so it doesn't cause more errors.
+    fn error_token_at(&mut self, message: &'static str, from: &'a [u8]) -> Token<'a> {
+        while self.peek().is_alphanumeric() || self.peek() == '_' {
+            self.advance();
+        }
+
+        let start = self.offset_of(from);
+        Token {
+            token_type: TokenType::Error,
+            start: message.as_bytes(),
             length: message.len(),
             line: self.line,
+            column: self.column_of(from),
+            span: Span {
+                start,
+                end: self.offset_of(self.current).max(start + 1),
+            },
         }
     }
 
+    fn span(&self) -> Span {
+        Span {
+            start: self.offset_of(self.start),
+            end: self.offset_of(self.current),
+        }
+    }
+
+    fn offset_of(&self, rest: &[u8]) -> usize {
+        self.source.len() - rest.len()
+    }
+
+    // The 1-based column where the remaining source starts, counted in characters.
+    fn column_of(&self, rest: &[u8]) -> usize {
+        self.source[self.line_start..self.offset_of(rest)]
+            .chars()
+            .count()
+            + 1
+    }
+
+    fn rest(&self) -> &'a str {
+        &self.source[self.offset_of(self.current)..]
+    }
+
+    fn newline(&mut self) {
+        self.line += 1;
+        self.line_start = self.offset_of(self.current);
+    }
+
     fn advance(&mut self) -> char {
-        let c = self.current[0] as char;
-        self.current = &self.current[1..];
-        return c;
+        let c = self.peek();
+        self.current = &self.current[c.len_utf8()..];
+        c
     }
 
     fn match_next(&mut self, expected: char) -> bool {
-        if self.is_at_end() {
+        if self.is_at_end() || self.peek() != expected {
             return false;
         }
-        if self.current[0] as char != expected {
-            return false;
-        }
-        self.current = &self.current[1..];
+        self.advance();
         true
     }
 
-    fn peek(&mut self) -> char {
-        if self.current.len() > 0 {
-            self.current[0] as char
-        } else {
-            return '\0';
-        }
+    fn peek(&self) -> char {
+        self.rest().chars().next().unwrap_or('\0')
     }
 
-    fn peek_next(&mut self) -> char {
-        if self.is_at_end() {
-            return '\0';
-        }
-
-        self.current[1] as char
+    fn peek_next(&self) -> char {
+        self.rest().chars().nth(1).unwrap_or('\0')
     }
 
     fn skip_whitespace(&mut self) {
@@ -267,9 +504,8 @@ impl<'a> Scanner<'a> {
                     // break; If we break we skip over Windows-style newlines (\r\n).
                 }
                 '\n' => {
-                    self.line += 1;
                     self.advance();
-                    break;
+                    self.newline();
                 }
                 '/' => {
                     if self.peek_next() == '/' {
@@ -279,80 +515,180 @@ impl<'a> Scanner<'a> {
                     } else {
                         return;
                     }
-                    break;
                 }
                 _ => break,
             }
         }
     }
 
-    fn string(&mut self) -> Token<'a> {
-        while self.peek() != '"' && !self.is_at_end() {
-            if self.peek() == '\n' {
-                self.line += 1;
-            }
+    fn string(&mut self, raw: bool) -> Token<'a> {
+        let triple_quoted = self.peek() == '"' && self.peek_next() == '"';
+        if triple_quoted {
+            self.advance();
             self.advance();
         }
+        self.string_contents(raw, triple_quoted)
+    }
 
-        if self.is_at_end() {
-            return self.error_token("Unterminated string.".to_string());
+    // Escapes are decoded by the compiler, here they're only skipped so an escaped quote
+    // doesn't end the string. Raw strings have no escapes.
+    //
+    // "a ${b} c" is scanned as an Interpolation token for '"a ${', the tokens of the expression,
+    // and a String token for '} c"'. Only plain strings can be interpolated.
+    fn string_contents(&mut self, raw: bool, triple_quoted: bool) -> Token<'a> {
+        loop {
+            if self.is_at_end() {
+                return self.error_token("Unterminated string.");
+            }
+            if self.peek() == '"' && (!triple_quoted || self.closes_triple_quote()) {
+                break;
+            }
+
+            let c = self.advance();
+            if c == '$' && self.peek() == '{' && !raw && !triple_quoted {
+                self.advance();
+                self.interpolations.push(0);
+                return self.make_token(TokenType::Interpolation);
+            }
+            if c == '\\' && !raw && !self.is_at_end() {
+                let escaped = self.advance();
+                if escaped == '\n' {
+                    self.newline();
+                }
+            } else if c == '\n' {
+                self.newline();
+            }
         }
 
-        self.advance();
-        return self.make_token(TokenType::String);
+        let closing_quotes = if triple_quoted { 3 } else { 1 };
+        for _ in 0..closing_quotes {
+            self.advance();
+        }
+        self.make_token(TokenType::String)
     }
 
-    fn number(&mut self) -> Token<'a> {
-        while self.peek().is_digit(10) {
-            self.advance();
+    fn closes_triple_quote(&self) -> bool {
+        self.current.starts_with(b"\"\"\"")
+    }
+
+    // Only checks the literal is well formed, the compiler parses its value.
+    // Supports 0x, 0b and 0o prefixes, '_' between digits, fractions, exponents,
+    // and a u8 suffix for byte literals.
+    fn number(&mut self, first: char) -> Token<'a> {
+        let mut radix = 10;
+        if first == '0' && matches!(self.peek(), 'x' | 'X' | 'b' | 'B' | 'o' | 'O') {
+            radix = match self.advance() {
+                'x' | 'X' => 16,
+                'b' | 'B' => 2,
+                _ => 8,
+            };
+            if !self.peek().is_digit(radix) {
+                return self.error_token_at("Expect digits after the base prefix.", self.start);
+            }
+        }
+        if let Err(error) = self.digits(radix) {
+            return error;
         }
 
-        if self.peek() == '.' && self.peek_next().is_digit(10) {
-            self.advance();
+        let mut is_float = false;
+        if radix == 10 {
+            if self.peek() == '.' && self.peek_next().is_ascii_digit() {
+                is_float = true;
+                self.advance();
+                if let Err(error) = self.digits(10) {
+                    return error;
+                }
+            }
 
-            while self.peek().is_digit(10) {
+            if matches!(self.peek(), 'e' | 'E') {
+                is_float = true;
+                let exponent = self.current;
                 self.advance();
+                if matches!(self.peek(), '+' | '-') {
+                    self.advance();
+                }
+                if !self.peek().is_ascii_digit() {
+                    return self.error_token_at("Expect digits in exponent.", exponent);
+                }
+                if let Err(error) = self.digits(10) {
+                    return error;
+                }
+            }
+        }
+
+        if self.peek() == 'u' && self.peek_next() == '8' {
+            if is_float {
+                return self.error_token_at("Byte literals must be integers.", self.current);
             }
+            self.advance();
+            self.advance();
+        }
+
+        if self.peek().is_alphanumeric() || self.peek() == '_' {
+            let message = match radix {
+                2 => "Invalid digit in binary literal.",
+                8 => "Invalid digit in octal literal.",
+                16 => "Invalid digit in hexadecimal literal.",
+                _ => "Invalid character in number literal.",
+            };
+            return self.error_token_at(message, self.current);
         }
 
         self.make_token(TokenType::Number)
     }
 
-    fn identifier(&mut self, first: char) -> Token<'a> {
-        let mut keyword: String = String::new();
-        keyword.push(first);
+    // Scans digits of the radix, the first one has already been checked or scanned.
+    fn digits(&mut self, radix: u32) -> Result<(), Token<'a>> {
+        loop {
+            let c = self.peek();
+            if c.is_digit(radix) {
+                self.advance();
+            } else if c == '_' {
+                if !self.peek_next().is_digit(radix) {
+                    return Err(self
+                        .error_token_at("Digit separator must be between digits.", self.current));
+                }
+                self.advance();
+            } else {
+                return Ok(());
+            }
+        }
+    }
 
-        while self.peek().is_alphabetic() || self.peek().is_digit(10) {
-            keyword.push(self.current[0] as char);
+    // Identifiers follow the Unicode XID rules, with '_' also allowed as the first character.
+    fn identifier(&mut self) -> Token<'a> {
+        while is_xid_continue(self.peek()) {
             self.advance();
         }
-        self.make_token(self.identifier_type(keyword.as_str()))
+        let text = &self.source[self.offset_of(self.start)..self.offset_of(self.current)];
+        self.make_token(self.identifier_type(text))
     }
 
-    fn identifier_type(&self, keyword: &str) -> TokenType {
-        let mut keywords = HashMap::new();
-        keywords.insert("and", TokenType::And);
-        keywords.insert("or", TokenType::Or);
-        keywords.insert("if", TokenType::If);
-        keywords.insert("else", TokenType::Else);
-        keywords.insert("false", TokenType::False);
-        keywords.insert("true", TokenType::True);
-        keywords.insert("fun", TokenType::Fun);
-        keywords.insert("nil", TokenType::Nil);
-        keywords.insert("print", TokenType::Print);
-        keywords.insert("return", TokenType::Return);
-        keywords.insert("while", TokenType::While);
-        keywords.insert("let", TokenType::Let);
-        keywords.insert("final", TokenType::Final);
-        keywords.insert("class", TokenType::Class);
-        keywords.insert("super", TokenType::Super);
-        keywords.insert("for", TokenType::For);
-        keywords.insert("this", TokenType::This);
-
-        if keywords.contains_key(keyword) {
-            keywords.get(keyword).unwrap().clone()
-        } else {
-            return TokenType::Identifier;
+    // A match compiles to comparisons on the length and bytes of the text,
+    // which is a lot cheaper than hashing every identifier.
+    fn identifier_type(&self, text: &str) -> TokenType {
+        match text {
+            "and" => TokenType::And,
+            "break" => TokenType::Break,
+            "class" => TokenType::Class,
+            "continue" => TokenType::Continue,
+            "else" => TokenType::Else,
+            "false" => TokenType::False,
+            "final" => TokenType::Final,
+            "for" => TokenType::For,
+            "fun" => TokenType::Fun,
+            "if" => TokenType::If,
+            "let" => TokenType::Let,
+            "match" => TokenType::Match,
+            "nil" => TokenType::Nil,
+            "or" => TokenType::Or,
+            "print" => TokenType::Print,
+            "return" => TokenType::Return,
+            "super" => TokenType::Super,
+            "this" => TokenType::This,
+            "true" => TokenType::True,
+            "while" => TokenType::While,
+            _ => TokenType::Identifier,
         }
     }
 }
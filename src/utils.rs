@@ -1,26 +1,163 @@
 use std::str;
 
-use crate::lnum::LNum;
+use num_bigint::BigInt;
 
-pub fn strtod_manual(input: &[u8]) -> Option<LNum> {
-    let input_str = str::from_utf8(input).ok()?;
+use crate::lnum::{LInt, LNum};
 
-    // Extract the numeric prefix
-    let numeric_part: String = input_str.chars().take_while(|c| c.is_digit(10)).collect();
+// Parses a number literal the scanner already checked, so only values that can't be
+// represented are errors.
+pub fn strtod_manual(input: &[u8]) -> Result<LNum, &'static str> {
+    let input_str = str::from_utf8(input).map_err(|_| "Invalid number literal.")?;
+    let (literal, is_byte) = match input_str.strip_suffix("u8") {
+        Some(literal) => (literal, true),
+        None => (input_str, false),
+    };
+    let literal = literal.replace('_', "");
 
-    if numeric_part.is_empty() {
-        None
-    } else {
-        let parsed = numeric_part.parse::<f64>().ok()?;
-        let lnum = LNum::new(parsed);
-        Some(lnum)
+    let (digits, radix) = match literal.get(..2) {
+        Some("0x" | "0X") => (&literal[2..], 16),
+        Some("0b" | "0B") => (&literal[2..], 2),
+        Some("0o" | "0O") => (&literal[2..], 8),
+        _ => (literal.as_str(), 10),
+    };
+
+    if radix == 10 && digits.contains(['.', 'e', 'E']) {
+        return digits
+            .parse::<f64>()
+            .map(LNum::Float)
+            .map_err(|_| "Invalid number literal.");
+    }
+
+    // Parsed as an integer first, going through f64 would lose precision above 2^53.
+    let int = match i64::from_str_radix(digits, radix) {
+        Ok(int) => LInt::new(int),
+        Err(_) => {
+            let huge =
+                BigInt::parse_bytes(digits.as_bytes(), radix).ok_or("Invalid number literal.")?;
+            LInt::from_big_int(huge)
+        }
+    };
+
+    if !is_byte {
+        return Ok(LNum::Int(int));
+    }
+    match int.to_i64().and_then(|byte| u8::try_from(byte).ok()) {
+        Some(byte) => Ok(LNum::Byte(byte)),
+        None => Err("Byte literal out of range, it must be between 0 and 255."),
     }
 }
 
+// Where an invalid escape sequence starts in the string's contents and how long it is.
+#[derive(Debug, Clone, PartialEq)]
+pub struct EscapeError {
+    pub offset: usize,
+    pub length: usize,
+    pub message: String,
+}
+
+// Decodes \n \t \r \0 \\ \" \' \$ and \u{...} with 1 to 6 hex digits.
+// An escaped '$' is how a string holds a literal '${'.
+pub fn unescape(raw: &str) -> Result<String, EscapeError> {
+    let mut result = String::with_capacity(raw.len());
+    let mut chars = raw.char_indices().peekable();
+
+    while let Some((offset, c)) = chars.next() {
+        if c != '\\' {
+            result.push(c);
+            continue;
+        }
+
+        let error = |length: usize, message: String| EscapeError {
+            offset,
+            length,
+            message,
+        };
+        match chars.next() {
+            Some((_, 'n')) => result.push('\n'),
+            Some((_, 't')) => result.push('\t'),
+            Some((_, 'r')) => result.push('\r'),
+            Some((_, '0')) => result.push('\0'),
+            Some((_, '\\')) => result.push('\\'),
+            Some((_, '"')) => result.push('"'),
+            Some((_, '\'')) => result.push('\''),
+            Some((_, '$')) => result.push('$'),
+            Some((_, 'u')) => {
+                if chars.next_if(|(_, c)| *c == '{').is_none() {
+                    return Err(error(2, "Expect '{' after '\\u'.".to_string()));
+                }
+                let mut hex = String::new();
+                while let Some((_, digit)) = chars.next_if(|(_, c)| c.is_ascii_hexdigit()) {
+                    hex.push(digit);
+                }
+                let length = 3 + hex.len();
+                if chars.next_if(|(_, c)| *c == '}').is_none() {
+                    return Err(error(
+                        length,
+                        "Expect '}' after the hex digits of a unicode escape.".to_string(),
+                    ));
+                }
+                let code_point = u32::from_str_radix(&hex, 16)
+                    .ok()
+                    .filter(|_| hex.len() <= 6)
+                    .and_then(char::from_u32);
+                match code_point {
+                    Some(c) => result.push(c),
+                    None => {
+                        return Err(error(
+                            length + 1,
+                            format!("Invalid unicode escape '\\u{{{}}}'.", hex),
+                        ))
+                    }
+                }
+            }
+            Some((_, other)) => {
+                return Err(error(
+                    1 + other.len_utf8(),
+                    format!("Unknown escape sequence '\\{}'.", other.escape_default()),
+                ))
+            }
+            None => return Err(error(1, "Expect a character after '\\'.".to_string())),
+        }
+    }
+
+    Ok(result)
+}
+
+// For triple-quoted strings. Drops the newline right after the opening quotes and the last line
+// when it only holds the indentation of the closing quotes, then removes the indentation all
+// lines share, so the string can be indented along with the code around it.
+pub fn strip_indentation(text: &str) -> String {
+    let text = text
+        .strip_prefix("\r\n")
+        .or_else(|| text.strip_prefix('\n'))
+        .unwrap_or(text);
+    let mut lines: Vec<&str> = text.split('\n').collect();
+
+    let is_blank = |line: &str| line.trim_matches([' ', '\t', '\r']).is_empty();
+    let indentation_of = |line: &str| line.len() - line.trim_start_matches([' ', '\t']).len();
+
+    // The closing quotes' indentation counts even though the line itself is dropped.
+    let mut indentation = usize::MAX;
+    if lines.len() > 1 && lines.last().is_some_and(|line| is_blank(line)) {
+        indentation = indentation_of(lines.pop().unwrap());
+    }
+    indentation = lines
+        .iter()
+        .filter(|line| !is_blank(line))
+        .map(|line| indentation_of(line))
+        .fold(indentation, usize::min);
+
+    lines
+        .iter()
+        .map(|line| line.get(indentation..).unwrap_or(""))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
 pub fn hash_str(chars: &[u8], length: usize) -> u32 {
     let mut hash: u32 = 2166136261;
-    for i in 0..length {
-        hash ^= chars[i] as u32;
+    for c in chars.iter().take(length) {
+        hash ^= *c as u32;
         hash = hash.wrapping_mul(16777619);
     }
 
@@ -1,51 +1,29 @@
+use num_bigint::BigInt;
+use num_traits::{FromPrimitive, ToPrimitive};
+
 use crate::{
     lnum::LNum,
-    object::{Obj, ObjString, ObjType},
+    object::{Obj, ObjFunction, ObjInstance, ObjList, ObjMap, ObjRef, ObjString, ObjType},
 };
 
-#[derive(Debug, Clone, PartialEq)]
-pub struct FinalValue {
-    pub value: Value,
-    pub is_final: bool,
-}
-
-impl FinalValue {
-    pub fn default() -> Self {
-        Self {
-            value: Value::Nil,
-            is_final: false,
-        }
-    }
-
-    pub fn default_with_value(value: Value) -> Self {
-        Self {
-            value,
-            is_final: false,
-        }
-    }
-
-    pub fn new(value: Value, is_final: bool) -> Self {
-        Self { value, is_final }
-    }
-
-    pub fn get_value(&self) -> &Value {
-        &self.value
-    }
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum HashKey {
+    Bool(bool),
+    Int(i64),
+    Huge(BigInt),
+    Float(u64),
+    String(String),
 }
 
 #[derive(Debug, Clone, PartialEq)]
 pub enum Value {
     Number(LNum),
     Bool(bool),
-    Object(Box<Obj>),
+    Object(ObjRef),
     Nil,
 }
 
 impl Value {
-    pub fn default() -> Self {
-        Value::Nil
-    }
-
     pub fn is_bool(&self) -> bool {
         matches!(self, Value::Bool(_))
     }
@@ -58,10 +36,6 @@ impl Value {
         matches!(self, Value::Number(_))
     }
 
-    pub fn is_object(&self) -> bool {
-        matches!(self, Value::Object(_))
-    }
-
     pub fn as_bool(&self) -> Option<bool> {
         if let Value::Bool(value) = self {
             Some(*value)
@@ -70,76 +44,101 @@ impl Value {
         }
     }
 
-    pub fn as_number(&self) -> Option<f64> {
-        if let Value::Number(value) = self {
-            Some(value.real_val())
+    pub fn as_object(&self) -> Option<&Obj> {
+        if let Value::Object(obj) = self {
+            Some(obj.as_obj())
         } else {
             None
         }
     }
 
-    pub fn as_object(&self) -> Option<Box<Obj>> {
-        if let Value::Object(obj) = self {
-            Some(obj.clone())
-        } else {
-            None
-        }
+    pub fn obj_type(&self) -> Option<ObjType> {
+        self.as_object().map(|obj| obj.obj_type())
     }
 
-    pub fn bool_val(value: bool) -> Self {
-        Value::Bool(value)
+    pub fn is_string(&self) -> bool {
+        matches!(self.obj_type(), Some(ObjType::String))
     }
 
-    pub fn nil_val() -> Self {
-        Value::Nil
+    pub fn as_string_obj(&self) -> Option<&ObjString> {
+        match self.as_object() {
+            Some(Obj::String(obj_string)) => Some(obj_string),
+            _ => None,
+        }
     }
 
-    pub fn number_val(value: f64) -> Self {
-        Value::Number(LNum::new(value))
+    pub fn as_function(&self) -> Option<&ObjFunction> {
+        match self.as_object() {
+            Some(Obj::Function(obj_function)) => Some(obj_function),
+            _ => None,
+        }
     }
 
-    pub fn obj_val(obj: Obj) -> Self {
-        Value::Object(Box::new(obj))
+    pub fn as_instance(&self) -> Option<&ObjInstance> {
+        match self.as_object() {
+            Some(Obj::Instance(obj_instance)) => Some(obj_instance),
+            _ => None,
+        }
     }
 
-    pub fn obj_type(&self) -> Option<ObjType> {
-        match self {
-            Value::Object(obj) => match &**obj {
-                Obj::String(_) => Some(ObjType::String),
-                Obj::Function(obj_function) => todo!(),
-            },
+    pub fn as_list(&self) -> Option<&ObjList> {
+        match self.as_object() {
+            Some(Obj::List(obj_list)) => Some(obj_list),
             _ => None,
         }
     }
 
-    pub fn is_string(&self) -> bool {
-        matches!(self.obj_type(), Some(ObjType::String))
+    pub fn as_map(&self) -> Option<&ObjMap> {
+        match self.as_object() {
+            Some(Obj::Map(obj_map)) => Some(obj_map),
+            _ => None,
+        }
     }
 
-    pub fn is_obj_type(&self, object_type: ObjType) -> bool {
-        // We can unwrap here because self.is_object confirms that we're dealing with an object.
-        self.is_object() && self.obj_type().unwrap() == object_type
+    // Only strings, numbers and booleans can be used as map keys.
+    pub fn hash_key(&self) -> Option<HashKey> {
+        match self {
+            Value::Bool(b) => Some(HashKey::Bool(*b)),
+            Value::Number(n) => {
+                let real = n.real_val();
+                let whole = n.as_big_int().or_else(|| {
+                    (real.fract() == 0.0)
+                        .then(|| BigInt::from_f64(real))
+                        .flatten()
+                });
+                match whole {
+                    Some(whole) => match whole.to_i64() {
+                        Some(i) => Some(HashKey::Int(i)),
+                        None => Some(HashKey::Huge(whole)),
+                    },
+                    None => Some(HashKey::Float(real.to_bits())),
+                }
+            }
+            Value::Object(_) => self.as_c_string().map(|s| HashKey::String(s.to_string())),
+            Value::Nil => None,
+        }
     }
 
-    pub fn as_string_obj(&self) -> Option<&ObjString> {
+    pub fn as_int(&self) -> Option<i64> {
         match self {
-            Value::Object(obj) => match &**obj {
-                Obj::String(obj_string) => Some(obj_string),
-                Obj::Function(obj_function) => todo!(),
-            },
+            Value::Number(n) => n.as_int(),
             _ => None,
         }
     }
 
+    pub fn is_instance(&self) -> bool {
+        matches!(self.obj_type(), Some(ObjType::Instance))
+    }
+
     pub fn as_c_string(&self) -> Option<&str> {
         self.as_string_obj().map(|s| s.as_str())
     }
 
     pub fn negate(&self) -> Result<Value, String> {
-        return match self {
+        match self {
             Value::Number(n) => Ok(Value::Number(n.negate())),
             _ => Err("Negation is only supported for numbers".to_string()),
-        };
+        }
     }
 
     pub fn is_same_type(&self, other: &Value) -> bool {
@@ -149,7 +148,7 @@ impl Value {
 
 #[derive(Debug, Clone, PartialEq)]
 pub struct ValueArray {
-    pub values: Vec<FinalValue>,
+    pub values: Vec<Value>,
 }
 
 impl ValueArray {
@@ -157,12 +156,8 @@ impl ValueArray {
         Self { values: Vec::new() }
     }
 
-    pub fn write_value(&mut self, value: Value, is_final: bool) {
-        self.values.push(FinalValue::new(value, is_final));
-    }
-
-    pub fn free(&mut self) {
-        self.values.clear();
+    pub fn write_value(&mut self, value: Value) {
+        self.values.push(value);
     }
 
     pub fn len(&self) -> usize {
@@ -176,10 +171,7 @@ impl std::fmt::Display for Value {
             Value::Bool(val) => write!(f, "{}", val),
             Value::Nil => write!(f, "nil"),
             Value::Number(val) => write!(f, "{}", val),
-            Value::Object(obj) => match &**obj {
-                Obj::String(obj_string) => write!(f, "{}", obj_string.as_str()),
-                Obj::Function(obj_function) => todo!(),
-            },
+            Value::Object(obj) => write!(f, "{}", obj.as_obj()),
         }
     }
 }
@@ -1,333 +1,1022 @@
-use std::io::{self, Write};
-
-use crate::chunk::ChunkWrite;
+use crate::chunk::Chunk;
 use crate::compiler::Compiler;
+use crate::core::Table;
 #[cfg(feature = "trace_exec")]
 use crate::debug::disassemble_instruction;
-use crate::lnum::LNum;
-use crate::object::{Obj, ObjString};
+use crate::error::{ErrorKind, LumiError, TraceFrame};
+use crate::lnum::{LInt, LNum, NumError, MAX_INT_BITS};
+use crate::memory::Heap;
+use crate::natives;
+use crate::object::{
+    NativeFn, Obj, ObjBoundMethod, ObjClass, ObjClosure, ObjFunction, ObjInstance, ObjList, ObjMap,
+    ObjNative, ObjRef, ObjString, ObjType, ObjUpvalue,
+};
 
-use crate::value::FinalValue;
+use crate::value::HashKey;
 use crate::{chunk::OpCode, value::Value};
 
-#[derive(Debug, PartialEq)]
-pub enum InterpretResult {
-    InterpretOk,
-    InterpretCompileError,
-    InterpretRuntimeError,
+const FRAMES_MAX: usize = 64;
+const STACK_MAX: usize = FRAMES_MAX * (u8::MAX as usize + 1);
+
+// A single ongoing function call.
+// slots is the index of the first stack slot the function can use, which holds the closure itself.
+#[derive(Debug, Clone, Copy)]
+struct CallFrame {
+    closure: ObjRef,
+    ip: *const u8,
+    slots: usize,
 }
 
-const STACK_MAX: usize = 256;
+impl CallFrame {
+    fn chunk(&self) -> &Chunk {
+        &self.function_obj().chunk
+    }
+
+    fn closure_obj(&self) -> &ObjClosure {
+        match self.closure.as_obj() {
+            Obj::Closure(closure) => closure,
+            _ => unreachable!("A call frame always holds a closure."),
+        }
+    }
+
+    fn function_obj(&self) -> &ObjFunction {
+        match self.closure_obj().function.as_obj() {
+            Obj::Function(function) => function,
+            _ => unreachable!("A closure always wraps a function."),
+        }
+    }
+
+    // The line and column of the instruction that was executed last in this frame.
+    fn location(&self) -> (usize, usize) {
+        let chunk = self.chunk();
+        let instruction = unsafe { self.ip.offset_from(chunk.code.as_ptr()) as usize - 1 };
+        (
+            chunk.lines[instruction] as usize,
+            chunk.columns[instruction],
+        )
+    }
+}
 
 // Our virtual machine.
 #[derive(Debug)]
-pub struct VM<'a> {
-    pub test_values: Vec<Value>, // Rather not have this in the vm, but it'll have to do for now when in full development.
-    compiler: Compiler<'a>,
-    ip: *const u8,
-    stack: [FinalValue; STACK_MAX],
-    stack_top: i32,
-    objects: Box<Vec<&'a Obj>>,
-    had_error: bool,
+pub struct VM {
+    // Every printed value, so tests can check the output. Only kept in tests since they stay
+    // alive until the vm is dropped.
+    #[cfg(test)]
+    pub test_values: Vec<Value>,
+    heap: Heap,
+    globals: Table,
+    // The names of final globals. The compiler rejects assigning them within a script,
+    // this catches assignments from a later script, like the next line in the REPL.
+    // Every key is also in globals, which keeps it from being collected.
+    final_globals: Table,
+    frames: Vec<CallFrame>,
+    stack: Vec<Value>,
+    stack_top: usize,
+    open_upvalues: Vec<ObjRef>,
+    init_string: ObjRef,
 }
 
-impl<'a> VM<'a> {
+impl VM {
     pub fn init_vm() -> Self {
-        Self {
+        let mut heap = Heap::new();
+        let init_string = heap.intern("init");
+
+        let mut vm = Self {
+            #[cfg(test)]
             test_values: Vec::new(),
-            compiler: Compiler::new(),
-            ip: std::ptr::null(),
-            stack: core::array::from_fn(|_| FinalValue::default()),
+            heap,
+            globals: Table::init(),
+            final_globals: Table::init(),
+            frames: Vec::with_capacity(FRAMES_MAX),
+            stack: vec![Value::Nil; STACK_MAX],
             stack_top: 0,
-            objects: Box::new(Vec::new()),
-            had_error: false,
+            open_upvalues: Vec::new(),
+            init_string,
+        };
+
+        vm.define_native("len", 1, natives::len);
+        vm.define_native("byte_len", 1, natives::byte_len);
+        vm.define_native("byte_at", 2, natives::byte_at);
+        vm.define_native("push", 2, natives::push);
+        vm.define_native("pop", 1, natives::pop);
+        vm.define_native("keys", 1, natives::keys);
+        vm.define_native("values", 1, natives::values);
+        vm.define_native("has", 2, natives::has);
+        vm.define_native("remove", 2, natives::remove);
+        vm
+    }
+
+    pub fn alloc(&mut self, obj: Obj) -> ObjRef {
+        if self.heap.should_collect() {
+            self.collect_garbage(Some(&obj));
+        }
+        self.heap.alloc(obj)
+    }
+
+    fn intern(&mut self, chars: &str) -> ObjRef {
+        if self.heap.should_collect() {
+            self.collect_garbage(None);
+        }
+        self.heap.intern(chars)
+    }
+
+    // Collections run before allocating, so the object being allocated isn't on the heap yet
+    // but the objects it refers to have to survive.
+    fn collect_garbage(&mut self, allocating: Option<&Obj>) {
+        for slot in &self.stack[..self.stack_top] {
+            self.heap.mark_value(slot);
+        }
+        for frame in &self.frames {
+            self.heap.mark_object(frame.closure);
         }
+        for upvalue in &self.open_upvalues {
+            self.heap.mark_object(*upvalue);
+        }
+        Self::mark_globals(&mut self.heap, &self.globals, self.init_string);
+        #[cfg(test)]
+        Self::mark_test_values(&mut self.heap, &self.test_values);
+
+        if let Some(obj) = allocating {
+            self.heap.mark_references(obj);
+        }
+        self.heap.collect();
     }
 
-    pub fn interpret(&mut self, code: &'a str) -> InterpretResult {
-        if !self.compiler.compile(code) {
-            self.compiler.chunk.free();
-            return InterpretResult::InterpretCompileError;
+    // The roots that outlive a single run, these are also marked while compiling.
+    fn mark_globals(heap: &mut Heap, globals: &Table, init_string: ObjRef) {
+        heap.mark_table(globals);
+        heap.mark_object(init_string);
+    }
+
+    #[cfg(test)]
+    fn mark_test_values(heap: &mut Heap, test_values: &[Value]) {
+        for value in test_values {
+            heap.mark_value(value);
         }
+    }
 
-        // Get the byte vector as a raw pointer to the memory address.
-        self.ip = self.compiler.chunk.code.as_ptr();
+    // Lowers the number of allocated bytes that triggers the next collection.
+    #[cfg(test)]
+    pub fn set_gc_threshold(&mut self, bytes: usize) {
+        self.heap.set_next_gc(bytes);
+    }
+
+    #[cfg(test)]
+    pub fn bytes_allocated(&self) -> usize {
+        self.heap.bytes_allocated()
+    }
+
+    // Called after adding to a list or map, so the collector sees the memory it grew by.
+    pub fn resize_object(&mut self, obj: ObjRef) {
+        self.heap.resize(obj);
+    }
+
+    fn define_native(&mut self, name: &str, arity: usize, function: NativeFn) {
+        // The name is defined first, so allocating the native can't collect it.
+        let key = self.intern(name);
+        self.globals.set(key, Value::Nil);
+        let native = self.alloc(Obj::Native(ObjNative::new(name, arity, function)));
+        self.globals.set(key, Value::Object(native));
+    }
+
+    // Runs the script and returns the value its top-level code returned, which is nil
+    // unless the script ended early.
+    pub fn interpret(&mut self, code: &str) -> Result<Value, LumiError> {
+        let globals = &self.globals;
+        let init_string = self.init_string;
+        #[cfg(test)]
+        let test_values = &self.test_values;
+        let mark_vm_roots = |heap: &mut Heap| {
+            Self::mark_globals(heap, globals, init_string);
+            #[cfg(test)]
+            Self::mark_test_values(heap, test_values);
+        };
+        let function = Compiler::new(&mut self.heap, &mark_vm_roots)
+            .compile(code)
+            .map_err(LumiError::compile)?;
+
+        self.push(Value::Object(function))?;
+        let closure = self.alloc(Obj::Closure(ObjClosure::new(function, Vec::new())));
+        self.pop();
+        self.push(Value::Object(closure))?;
+        self.call(closure, 0)?;
 
         let result = self.run();
-        self.compiler.chunk.free();
         self.reset_stack();
 
         result
     }
 
     pub fn free_vm(&mut self) {
-        self.ip = std::ptr::null();
-        self.stack = core::array::from_fn(|_| FinalValue::default());
+        self.frames.clear();
+        self.stack = vec![Value::Nil; STACK_MAX];
         self.stack_top = 0;
-        self.objects = Box::new(Vec::new());
-        self.had_error = false;
-        self.compiler.chunk.free();
-        self.compiler.globals.free();
-        self.compiler.strings.free();
+        self.globals.free();
+        self.final_globals.free();
+        self.heap.free();
     }
 
     fn reset_stack(&mut self) {
         self.stack_top = 0;
+        self.frames.clear();
+        self.open_upvalues.clear();
     }
 
-    fn runtime_error(&mut self, message: &str) -> InterpretResult {
-        let stderr = io::stderr();
-        let mut handle = stderr.lock();
-        writeln!(handle, "{}", message).unwrap();
+    // Builds the error with a trace of the active calls and unwinds the stack.
+    fn runtime_error(&mut self, kind: ErrorKind, message: &str) -> LumiError {
+        let trace: Vec<TraceFrame> = self
+            .frames
+            .iter()
+            .rev()
+            .map(|frame| {
+                let (line, column) = frame.location();
+                TraceFrame {
+                    function: frame.function_obj().name.as_ref().map(|n| n.to_string()),
+                    line,
+                    column,
+                }
+            })
+            .collect();
 
-        let instruction =
-            unsafe { self.ip.offset_from(self.compiler.chunk.code.as_ptr()) as usize - 1 };
-        let line = self.compiler.chunk.lines[instruction];
-        writeln!(handle, "[line {}] in script", line).unwrap();
+        let (line, column) = trace
+            .first()
+            .map_or((0, 0), |frame| (frame.line, frame.column));
+        let mut error = LumiError::new(kind, message, line, column);
+        error.trace = trace;
 
-        // FIXME: stack is not synced anymore after runtime
         self.reset_stack();
-        return InterpretResult::InterpretRuntimeError;
+        error
+    }
+
+    fn frame(&self) -> &CallFrame {
+        self.frames
+            .last()
+            .expect("There is always a frame while running.")
+    }
+
+    fn frame_mut(&mut self) -> &mut CallFrame {
+        self.frames
+            .last_mut()
+            .expect("There is always a frame while running.")
     }
 
     // Moves the pointer forward 1 byte.
     unsafe fn read_byte(&mut self) -> u8 {
-        let b = *self.ip;
-        self.ip = self.ip.add(1);
+        let frame = self.frame_mut();
+        let b = *frame.ip;
+        frame.ip = frame.ip.add(1);
         b
     }
 
     // Moves the pointer forward 2 bytes.
     unsafe fn read_short(&mut self) -> u16 {
-        let high = *self.ip as u16;
-        let low = *self.ip.add(1) as u16;
-        self.ip = self.ip.add(2);
+        let frame = self.frame_mut();
+        let high = *frame.ip as u16;
+        let low = *frame.ip.add(1) as u16;
+        frame.ip = frame.ip.add(2);
         (high << 8) | low
     }
 
-    fn read_constant(&mut self) -> FinalValue {
+    fn read_constant(&mut self) -> Value {
         let index = unsafe { self.read_byte() } as usize;
-        self.compiler.chunk.constants.values[index].clone()
+        self.frame().chunk().constants.values[index].clone()
+    }
+
+    // Reads a constant the compiler guarantees to be a string, like a variable or property name.
+    fn read_string(&mut self) -> ObjRef {
+        match self.read_constant() {
+            Value::Object(obj) if obj.as_obj().obj_type() == ObjType::String => obj,
+            _ => unreachable!("Names are always stored as string constants."),
+        }
     }
 
-    fn binary_op<F>(&mut self, op: F)
+    fn binary_op<F>(&mut self, op: F) -> Result<(), LumiError>
     where
-        F: FnOnce(f64, f64) -> f64,
+        F: FnOnce(LNum, LNum) -> Result<LNum, NumError>,
     {
-        if !self.peek(0).value.is_number() || !self.peek(1).value.is_number() {
-            self.runtime_error("Operands must be numbers.");
-            self.had_error = true;
-            return;
+        if !self.peek(0).is_number() || !self.peek(1).is_number() {
+            return Err(self.runtime_error(ErrorKind::Type, "Operands must be numbers."));
         }
-        let b = self.pop().value.clone();
-        let a = self.pop().value.clone();
+        let b = self.pop().clone();
+        let a = self.pop().clone();
         if let (Value::Number(b), Value::Number(a)) = (b, a) {
-            let b_val = b.real_val();
-            let a_val = a.real_val();
-            self.push(FinalValue::default_with_value(Value::Number(LNum::new(op(
-                a_val, b_val,
-            )))));
+            match op(a, b) {
+                Ok(result) => self.push(Value::Number(result))?,
+                Err(error) => return Err(self.num_error(error)),
+            }
         }
+        Ok(())
     }
 
-    fn binary_op_bool<F>(&mut self, op: F)
+    fn num_error(&mut self, error: NumError) -> LumiError {
+        match error {
+            NumError::DivisionByZero => {
+                self.runtime_error(ErrorKind::Arithmetic, "Division by zero.")
+            }
+            NumError::NotAnInteger => {
+                self.runtime_error(ErrorKind::Type, "Operands must be integers.")
+            }
+            NumError::ShiftOutOfRange => self.runtime_error(
+                ErrorKind::Arithmetic,
+                "Shift amount must be a non-negative integer that fits in 32 bits.",
+            ),
+            NumError::TooLarge => self.runtime_error(
+                ErrorKind::Arithmetic,
+                &format!("Integer result would have more than {} bits.", MAX_INT_BITS),
+            ),
+        }
+    }
+
+    fn binary_op_bool<F>(&mut self, op: F) -> Result<(), LumiError>
     where
-        F: FnOnce(f64, f64) -> bool,
+        F: FnOnce(LNum, LNum) -> bool,
     {
-        if !self.peek(0).value.is_number() || !self.peek(1).value.is_number() {
-            self.runtime_error("Operands must be numbers.");
-            self.had_error = true;
-            return;
+        if !self.peek(0).is_number() || !self.peek(1).is_number() {
+            return Err(self.runtime_error(ErrorKind::Type, "Operands must be numbers."));
         }
-        let b = self.pop().value.clone();
-        let a = self.pop().value.clone();
+        let b = self.pop().clone();
+        let a = self.pop().clone();
         if let (Value::Number(b), Value::Number(a)) = (b, a) {
-            let b_val = b.real_val();
-            let a_val = a.real_val();
-            self.push(FinalValue::default_with_value(Value::Bool(op(a_val, b_val))));
+            self.push(Value::Bool(op(a, b)))?;
         }
+        Ok(())
     }
 
-    fn run(&mut self) -> InterpretResult {
+    fn run(&mut self) -> Result<Value, LumiError> {
         loop {
             #[cfg(feature = "trace_exec")]
             trace_execution(self);
 
-            if self.had_error {
-                self.had_error = false;
-                return InterpretResult::InterpretRuntimeError;
-            }
-
             let instruction = unsafe { self.read_byte() };
             match OpCode::from_u8(instruction) {
                 Some(OpCode::Constant) => {
-                    let fin_val = self.read_constant();
-                    let constant = fin_val.value;
-                    if constant.is_object() {
-                        let obj = constant.as_object().unwrap();
-                        self.objects.push(Box::leak(Box::new(obj.clone())));
-                    }
-                    self.push(FinalValue::new(constant, fin_val.is_final));
+                    let constant = self.read_constant();
+                    self.push(constant)?;
                 }
                 Some(OpCode::Negate) => {
-                    if !self.peek(0).value.is_number() {
-                        return self.runtime_error("Operand must be a number.");
+                    if !self.peek(0).is_number() {
+                        return Err(
+                            self.runtime_error(ErrorKind::Type, "Operand must be a number.")
+                        );
                     }
                     let value = self.pop().clone();
-                    match value.value.negate() {
-                        Ok(negated_value) => self.push(FinalValue::default_with_value(negated_value)),
+                    match value.negate() {
+                        Ok(negated_value) => self.push(negated_value)?,
                         Err(err) => panic!("{}", err),
                     }
                 }
                 Some(OpCode::Add) => {
-                    if self.peek(0).value.is_string() && self.peek(1).value.is_string() {
-                        self.concatenate();
-                    } else if self.peek(0).value.is_number() && self.peek(1).value.is_number() {
-                        let b = self.pop().value.clone();
-                        let a = self.pop().value.clone();
-                        if let (Value::Number(b), Value::Number(a)) = (b, a) {
-                            let b_val = b.real_val();
-                            let a_val = a.real_val();
-                            self.push(FinalValue::default_with_value(Value::Number(LNum::new(
-                                a_val + b_val,
-                            ))));
-                        }
+                    if self.peek(0).is_string() && self.peek(1).is_string() {
+                        self.concatenate()?;
+                    } else if self.peek(0).is_number() && self.peek(1).is_number() {
+                        self.binary_op(|a, b| Ok(a + b))?;
                     } else {
-                        return self.runtime_error("Operands must be two numbers or two strings.");
+                        return Err(self.runtime_error(
+                            ErrorKind::Type,
+                            "Operands must be two numbers or two strings.",
+                        ));
                     }
                 }
                 Some(OpCode::Subtract) => {
-                    self.binary_op(|a, b| a - b);
+                    self.binary_op(|a, b| Ok(a - b))?;
                 }
                 Some(OpCode::Multiply) => {
-                    self.binary_op(|a, b| a * b);
+                    self.binary_op(|a, b| Ok(a * b))?;
                 }
                 Some(OpCode::Divide) => {
-                    self.binary_op(|a, b| a / b);
+                    self.binary_op(|a, b| a.divide(&b))?;
+                }
+                Some(OpCode::IntDivide) => self.binary_op(|a, b| a.int_divide(&b))?,
+                Some(OpCode::Modulo) => self.binary_op(|a, b| a.modulo(&b))?,
+                Some(OpCode::Power) => self.binary_op(|a, b| a.power(&b))?,
+                Some(OpCode::BitAnd) => self.binary_op(|a, b| a.bit_and(&b))?,
+                Some(OpCode::BitOr) => self.binary_op(|a, b| a.bit_or(&b))?,
+                Some(OpCode::BitXor) => self.binary_op(|a, b| a.bit_xor(&b))?,
+                Some(OpCode::ShiftLeft) => self.binary_op(|a, b| a.shift_left(&b))?,
+                Some(OpCode::ShiftRight) => self.binary_op(|a, b| a.shift_right(&b))?,
+                Some(OpCode::Stringify) => {
+                    if !self.peek(0).is_string() {
+                        let value = self.pop().clone();
+                        let obj = self.intern(&value.to_string());
+                        self.push(Value::Object(obj))?;
+                    }
+                }
+                Some(OpCode::BitNot) => {
+                    let result = match &self.peek(0) {
+                        Value::Number(n) => n.bit_not().ok(),
+                        _ => None,
+                    };
+                    let Some(result) = result else {
+                        return Err(
+                            self.runtime_error(ErrorKind::Type, "Operand must be an integer.")
+                        );
+                    };
+                    self.pop();
+                    self.push(Value::Number(result))?;
+                }
+                Some(op @ (OpCode::Increment | OpCode::Decrement)) => {
+                    let one = LNum::Int(LInt::new(1));
+                    let result = match self.peek(0) {
+                        Value::Number(n) if op == OpCode::Increment => n.clone() + one,
+                        Value::Number(n) => n.clone() - one,
+                        _ => {
+                            return Err(
+                                self.runtime_error(ErrorKind::Type, "Operand must be a number.")
+                            )
+                        }
+                    };
+                    self.pop();
+                    self.push(Value::Number(result))?;
+                }
+                Some(OpCode::Dup) => {
+                    let value = self.peek(0).clone();
+                    self.push(value)?;
                 }
                 Some(OpCode::Not) => {
                     let value = self.pop().clone();
-                    let is_falsey = self.is_falsey(value.value);
-                    self.push(FinalValue::default_with_value(Value::Bool(is_falsey)));
+                    let is_falsey = self.is_falsey(value);
+                    self.push(Value::Bool(is_falsey))?;
                 }
-                Some(OpCode::Nil) => self.push(FinalValue::default_with_value(Value::Nil)),
-                Some(OpCode::True) => self.push(FinalValue::default_with_value(Value::Bool(true))),
-                Some(OpCode::False) => self.push(FinalValue::default_with_value(Value::Bool(false))),
+                Some(OpCode::Nil) => self.push(Value::Nil)?,
+                Some(OpCode::True) => self.push(Value::Bool(true))?,
+                Some(OpCode::False) => self.push(Value::Bool(false))?,
                 Some(OpCode::Equal) => {
                     let a = self.pop().clone();
                     let b = self.pop().clone();
-                    self.push(FinalValue::default_with_value(Value::Bool(
-                        self.values_equal(a.value, b.value),
-                    )));
+                    self.push(Value::Bool(self.values_equal(a, b)))?;
                 }
-                Some(OpCode::Greater) => self.binary_op_bool(|a, b| a > b),
-                Some(OpCode::Less) => self.binary_op_bool(|a, b| a < b),
+                Some(OpCode::Greater) => self.binary_op_bool(|a, b| a > b)?,
+                Some(OpCode::Less) => self.binary_op_bool(|a, b| a < b)?,
                 Some(OpCode::Return) => {
-                    return InterpretResult::InterpretOk;
+                    let result = self.pop().clone();
+                    let slots = self.frame().slots;
+                    self.close_upvalues(slots);
+                    let frame = self.frames.pop().unwrap();
+                    if self.frames.is_empty() {
+                        // Pop the script function itself.
+                        self.pop();
+                        return Ok(result);
+                    }
+
+                    // Discard the arguments and locals of the function that returned.
+                    self.stack_top = frame.slots;
+                    self.push(result)?;
                 }
                 Some(OpCode::Print) => {
                     let res = self.pop().clone();
-                    println!("{}", res.value);
+                    println!("{}", res);
 
-                    self.test_values.push(res.value);
+                    #[cfg(test)]
+                    self.test_values.push(res);
                 }
                 Some(OpCode::Pop) => {
                     self.pop();
                 }
-                Some(OpCode::DefineGlobal) => {
-                    let var_name = self.read_constant().value;
-                    if let Some(key) = var_name.as_string_obj().clone() {
-                        let var_val = self.peek(0).clone();
-                        self.compiler.globals.set(key.hash, var_val.value);
-                        self.pop();
-                        // We pop after the value has been added to the hashtable.
-                        // That ensures the VM can still find the variable if a garbage collection.
-                        // is triggered right in the middle of adding it to the hash table.
-                    } else {
-                        return self.runtime_error("Constant is not a string.");
+                Some(op @ (OpCode::DefineGlobal | OpCode::DefineFinalGlobal)) => {
+                    let key = self.read_string();
+                    if self.final_globals.get(key).is_some() {
+                        return Err(self.final_error(key, "redeclared"));
                     }
+                    if op == OpCode::DefineFinalGlobal {
+                        self.final_globals.set(key, Value::Nil);
+                    }
+                    let var_val = self.peek(0).clone();
+                    self.globals.set(key, var_val);
+                    self.pop();
+                    // We pop after the value has been added to the hashtable.
+                    // That ensures the VM can still find the variable if a garbage collection.
+                    // is triggered right in the middle of adding it to the hash table.
                 }
                 Some(OpCode::GetGlobal) => {
-                    let fin_value = self.read_constant();
-                    let var_name = fin_value.value;
-                    if let Some(key) = var_name.as_string_obj().clone() {
-                        if let Some(value) = self.compiler.globals.get(key.hash) {
-                            self.push(FinalValue::new(value.clone(), fin_value.is_final));
-                        } else {
-                            return self.runtime_error(
-                                format!("Undefined variable {}.", key.as_str()).as_str(),
-                            );
-                        }
+                    let key = self.read_string();
+                    if let Some(value) = self.globals.get(key) {
+                        self.push(value.clone())?;
                     } else {
-                        return self.runtime_error("Constant is not a string.");
+                        return Err(self.runtime_error(
+                            ErrorKind::UndefinedVariable,
+                            format!("Undefined variable {}.", key.as_obj()).as_str(),
+                        ));
                     }
                 }
                 Some(OpCode::SetGlobal) => {
-                    let final_val = self.read_constant();
-                    if final_val.is_final {
-                        return self.var_final_error(&final_val);
+                    let key = self.read_string();
+                    if self.final_globals.get(key).is_some() {
+                        return Err(self.final_error(key, "modified"));
                     }
-                    let var_name = final_val.value;
-                    if let Some(key) = var_name.as_string_obj().clone() {
-                        let var_val = self.peek(0).clone();
-                        if self.compiler.globals.set(key.hash, var_val.value) {
-                            self.compiler.globals.delete(key.hash);
-                            self.runtime_error(
-                                format!("Undefined variable {}.", key.as_str()).as_str(),
-                            );
-                            return InterpretResult::InterpretRuntimeError;
-                        }
+                    let var_val = self.peek(0).clone();
+                    if self.globals.set(key, var_val) {
+                        self.globals.delete(key);
+                        return Err(self.runtime_error(
+                            ErrorKind::UndefinedVariable,
+                            format!("Undefined variable {}.", key.as_obj()).as_str(),
+                        ));
                     }
                 }
                 Some(OpCode::SetLocal) => {
                     let slot = unsafe { self.read_byte() } as usize;
                     let value_to_add_to_stack = self.peek(0).clone();
-                    if value_to_add_to_stack.is_final {
-                        return self.var_final_error(&value_to_add_to_stack);
-                    }
-                    self.stack[slot as usize] = self.peek(0).clone();
+                    let slots = self.frame().slots;
+                    self.stack[slots + slot] = value_to_add_to_stack;
                 }
                 Some(OpCode::GetLocal) => {
                     let slot = unsafe { self.read_byte() } as usize;
-                    self.push(self.stack[slot].clone());
+                    let slots = self.frame().slots;
+                    self.push(self.stack[slots + slot].clone())?;
                 }
                 Some(OpCode::Jump) => {
                     let offset = unsafe { self.read_short() };
-                    self.ip = unsafe { self.ip.add(offset as usize) };
+                    let frame = self.frame_mut();
+                    frame.ip = unsafe { frame.ip.add(offset as usize) };
                 }
                 Some(OpCode::JumpIfFalse) => {
                     let offset = unsafe { self.read_short() };
-                    let value = self.peek(0).value.clone();
+                    let value = self.peek(0).clone();
                     if self.is_falsey(value) {
-                        self.ip = unsafe { self.ip.add(offset as usize) };
+                        let frame = self.frame_mut();
+                        frame.ip = unsafe { frame.ip.add(offset as usize) };
+                    }
+                }
+                Some(OpCode::InRange) => {
+                    let inclusive = unsafe { self.read_byte() } == 1;
+                    let upper = self.pop().clone();
+                    let lower = self.pop().clone();
+                    let value = self.pop().clone();
+                    // Only numbers are in a range, anything else just doesn't match.
+                    let in_range = match (value, lower, upper) {
+                        (Value::Number(n), Value::Number(lower), Value::Number(upper)) => {
+                            n >= lower && (n < upper || inclusive && n == upper)
+                        }
+                        _ => false,
+                    };
+                    self.push(Value::Bool(in_range))?;
+                }
+                Some(OpCode::JumpTable) => {
+                    let lowest = self.read_constant().as_int();
+                    let cases = unsafe { self.read_byte() } as usize;
+                    // Integral floats pick a case too, 2.0 matches 2 like it does with ==.
+                    let case = match (self.pop().hash_key(), lowest) {
+                        (Some(HashKey::Int(value)), Some(lowest)) => value
+                            .checked_sub(lowest)
+                            .and_then(|case| usize::try_from(case).ok())
+                            .filter(|case| *case < cases),
+                        _ => None,
+                    }
+                    .unwrap_or(cases);
+
+                    let frame = self.frame_mut();
+                    unsafe {
+                        let entry = frame.ip.add(case * 2);
+                        let offset = ((*entry as usize) << 8) | *entry.add(1) as usize;
+                        frame.ip = frame.ip.add((cases + 1) * 2).sub(offset);
                     }
                 }
                 Some(OpCode::Loop) => {
                     let offset = unsafe { self.read_short() };
-                    self.ip = unsafe { self.ip.sub(offset as usize) };
+                    let frame = self.frame_mut();
+                    frame.ip = unsafe { frame.ip.sub(offset as usize) };
+                }
+                Some(OpCode::GetUpvalue) => {
+                    let slot = unsafe { self.read_byte() } as usize;
+                    let upvalue = self.frame().closure_obj().upvalues[slot];
+                    let value = match upvalue.as_obj() {
+                        Obj::Upvalue(ObjUpvalue {
+                            closed: Some(value),
+                            ..
+                        }) => value.clone(),
+                        Obj::Upvalue(ObjUpvalue { location, .. }) => self.stack[*location].clone(),
+                        _ => unreachable!("A closure only captures upvalues."),
+                    };
+                    self.push(value)?;
+                }
+                Some(OpCode::SetUpvalue) => {
+                    let slot = unsafe { self.read_byte() } as usize;
+                    let value = self.peek(0).clone();
+                    let mut upvalue = self.frame().closure_obj().upvalues[slot];
+                    if let Obj::Upvalue(obj_upvalue) = upvalue.as_obj_mut() {
+                        match obj_upvalue.closed.as_mut() {
+                            Some(closed) => *closed = value,
+                            None => self.stack[obj_upvalue.location] = value,
+                        }
+                    }
+                }
+                Some(OpCode::CloseUpvalue) => {
+                    self.close_upvalues(self.stack_top - 1);
+                    self.pop();
                 }
-                _ => return InterpretResult::InterpretRuntimeError,
+                Some(OpCode::Closure) => {
+                    let function = self.read_constant();
+                    let (function, upvalue_count) = match &function {
+                        Value::Object(obj) => (*obj, function.as_function().unwrap().upvalue_count),
+                        _ => unreachable!("A closure is always created from a function constant."),
+                    };
+
+                    let mut upvalues = Vec::with_capacity(upvalue_count);
+                    for _ in 0..upvalue_count {
+                        let is_local = unsafe { self.read_byte() } == 1;
+                        let index = unsafe { self.read_byte() } as usize;
+                        if is_local {
+                            let location = self.frame().slots + index;
+                            upvalues.push(self.capture_upvalue(location));
+                        } else {
+                            upvalues.push(self.frame().closure_obj().upvalues[index]);
+                        }
+                    }
+
+                    let closure = self.alloc(Obj::Closure(ObjClosure::new(function, upvalues)));
+                    self.push(Value::Object(closure))?;
+                }
+                Some(OpCode::Class) => {
+                    let name = self.read_constant();
+                    let name = name.as_string_obj().unwrap().clone();
+                    let class = self.alloc(Obj::Class(ObjClass::new(name)));
+                    self.push(Value::Object(class))?;
+                }
+                Some(OpCode::GetProperty) => {
+                    let name = self.read_string();
+                    let receiver = self.peek(0).clone();
+                    let instance = match receiver.as_instance() {
+                        Some(instance) => instance,
+                        None => {
+                            return Err(self
+                                .runtime_error(ErrorKind::Type, "Only instances have properties."))
+                        }
+                    };
+
+                    // Fields shadow methods with the same name.
+                    if let Some(value) = instance.fields.get(name) {
+                        let value = value.clone();
+                        self.pop();
+                        self.push(value)?;
+                    } else {
+                        self.bind_method(instance.class, name)?;
+                    }
+                }
+                Some(OpCode::SetProperty) => {
+                    let name = self.read_string();
+                    let mut instance = match &self.peek(1) {
+                        Value::Object(obj) if self.peek(1).is_instance() => *obj,
+                        _ => {
+                            return Err(
+                                self.runtime_error(ErrorKind::Type, "Only instances have fields.")
+                            )
+                        }
+                    };
+
+                    let value = self.pop().clone();
+                    if let Obj::Instance(obj_instance) = instance.as_obj_mut() {
+                        obj_instance.fields.set(name, value.clone());
+                    }
+                    // Pop the instance and leave the assigned value behind.
+                    self.pop();
+                    self.push(value)?;
+                }
+                Some(OpCode::Method) => {
+                    let name = self.read_string();
+                    self.define_method(name);
+                }
+                Some(OpCode::Inherit) => {
+                    let superclass = match &self.peek(1) {
+                        Value::Object(obj) if matches!(obj.as_obj(), Obj::Class(_)) => *obj,
+                        _ => {
+                            return Err(
+                                self.runtime_error(ErrorKind::Type, "Superclass must be a class.")
+                            )
+                        }
+                    };
+                    if let Value::Object(mut subclass) = self.peek(0) {
+                        if let (Obj::Class(from), Obj::Class(to)) =
+                            (superclass.as_obj(), subclass.as_obj_mut())
+                        {
+                            // Copy the inherited methods down before the subclass defines its own,
+                            // so overrides replace them.
+                            from.methods.add_all(&mut to.methods);
+                        }
+                    }
+                    self.pop();
+                }
+                Some(OpCode::GetSuper) => {
+                    let name = self.read_string();
+                    let superclass = match &self.pop() {
+                        Value::Object(obj) if matches!(obj.as_obj(), Obj::Class(_)) => *obj,
+                        _ => {
+                            return Err(self.runtime_error(
+                                ErrorKind::Type,
+                                "Can't use 'super' in a class with no superclass.",
+                            ))
+                        }
+                    };
+
+                    self.bind_method(superclass, name)?;
+                }
+                Some(OpCode::BuildList) => {
+                    let count = unsafe { self.read_byte() } as usize;
+                    let items = self.stack[self.stack_top - count..self.stack_top].to_vec();
+                    self.stack_top -= count;
+
+                    let list = self.alloc(Obj::List(ObjList::new(items)));
+                    self.push(Value::Object(list))?;
+                }
+                Some(OpCode::BuildMap) => {
+                    let count = unsafe { self.read_byte() } as usize;
+                    let mut map = ObjMap::new();
+                    for i in (0..count).rev() {
+                        let key = self.peek(i * 2 + 1).clone();
+                        let value = self.peek(i * 2).clone();
+                        let hash_key = self.hash_key(&key)?;
+                        map.set(hash_key, key, value);
+                    }
+                    self.stack_top -= count * 2;
+
+                    let map = self.alloc(Obj::Map(map));
+                    self.push(Value::Object(map))?;
+                }
+                Some(OpCode::GetIndex) => {
+                    let index = self.pop().clone();
+                    let target = self.pop().clone();
+                    let item = match target.as_object() {
+                        Some(Obj::List(list)) => list.items[self.list_index(list, &index)?].clone(),
+                        Some(Obj::String(string)) => {
+                            let c = self.string_index(string, &index)?;
+                            Value::Object(self.intern(c.encode_utf8(&mut [0; 4])))
+                        }
+                        Some(Obj::Map(map)) => match map.get(&self.hash_key(&index)?) {
+                            Some(item) => item.clone(),
+                            None => {
+                                return Err(self.runtime_error(
+                                    ErrorKind::UndefinedKey,
+                                    format!("Undefined key '{}'.", index).as_str(),
+                                ))
+                            }
+                        },
+                        _ => {
+                            return Err(self.runtime_error(
+                                ErrorKind::Type,
+                                "Only lists, maps and strings can be indexed.",
+                            ))
+                        }
+                    };
+                    self.push(item)?;
+                }
+                Some(OpCode::SetIndex) => {
+                    let value = self.pop().clone();
+                    let index = self.pop().clone();
+                    let mut target = match self.pop() {
+                        Value::Object(obj) => *obj,
+                        _ => {
+                            return Err(self.runtime_error(
+                                ErrorKind::Type,
+                                "Only lists and maps support index assignment.",
+                            ))
+                        }
+                    };
+
+                    match target.as_obj_mut() {
+                        Obj::List(list) => {
+                            let index = self.list_index(list, &index)?;
+                            list.items[index] = value.clone();
+                        }
+                        Obj::Map(map) => {
+                            let hash_key = self.hash_key(&index)?;
+                            map.set(hash_key, index, value.clone());
+                            self.heap.resize(target);
+                        }
+                        _ => {
+                            return Err(self.runtime_error(
+                                ErrorKind::Type,
+                                "Only lists and maps support index assignment.",
+                            ))
+                        }
+                    }
+                    self.push(value)?;
+                }
+                Some(OpCode::Call) => {
+                    let arg_count = unsafe { self.read_byte() } as usize;
+                    let callee = self.peek(arg_count).clone();
+                    self.call_value(callee, arg_count)?;
+                }
+                None => unreachable!("Unknown opcode {}.", instruction),
             };
         }
     }
 
-    fn push(&mut self, value: FinalValue) {
-        if (self.stack_top as usize) < STACK_MAX {
-            self.stack[self.stack_top as usize] = value;
-            self.stack_top += 1;
-        } else {
-            panic!("Stack overflow.");
+    fn call_value(&mut self, callee: Value, arg_count: usize) -> Result<(), LumiError> {
+        if let Value::Object(obj) = callee {
+            let receiver_slot = self.stack_top - arg_count - 1;
+            match obj.as_obj() {
+                Obj::BoundMethod(bound_method) => {
+                    self.stack[receiver_slot] = bound_method.receiver.clone();
+                    return self.call(bound_method.method, arg_count);
+                }
+                Obj::Class(class) => {
+                    let initializer = class.methods.get(self.init_string).cloned();
+                    let instance = self.alloc(Obj::Instance(ObjInstance::new(obj)));
+                    self.stack[receiver_slot] = Value::Object(instance);
+
+                    if let Some(Value::Object(initializer)) = initializer {
+                        return self.call(initializer, arg_count);
+                    } else if arg_count != 0 {
+                        return Err(self.runtime_error(
+                            ErrorKind::Arity,
+                            format!("Expected 0 arguments but got {}.", arg_count).as_str(),
+                        ));
+                    }
+                    return Ok(());
+                }
+                Obj::Closure(_) => return self.call(obj, arg_count),
+                Obj::Native(native) => {
+                    if arg_count != native.arity {
+                        return Err(self.runtime_error(
+                            ErrorKind::Arity,
+                            format!("Expected {} arguments but got {}.", native.arity, arg_count)
+                                .as_str(),
+                        ));
+                    }
+
+                    let args = self.stack[self.stack_top - arg_count..self.stack_top].to_vec();
+                    return match (native.function)(self, args) {
+                        Ok(result) => {
+                            self.stack_top = receiver_slot;
+                            self.push(result)?;
+                            Ok(())
+                        }
+                        Err(message) => Err(self.runtime_error(ErrorKind::Native, &message)),
+                    };
+                }
+                _ => {}
+            }
+        }
+
+        Err(self.runtime_error(ErrorKind::Type, "Can only call functions and classes."))
+    }
+
+    fn list_index(&mut self, list: &ObjList, index: &Value) -> Result<usize, LumiError> {
+        match self
+            .integer_index(index, "List")?
+            .and_then(|index| list.index(index))
+        {
+            Some(index) => Ok(index),
+            None => Err(self.runtime_error(
+                ErrorKind::IndexOutOfRange,
+                format!(
+                    "List index {} out of range for list of length {}.",
+                    index,
+                    list.items.len()
+                )
+                .as_str(),
+            )),
+        }
+    }
+
+    // Strings are indexed by character, not by byte.
+    fn string_index(&mut self, string: &ObjString, index: &Value) -> Result<char, LumiError> {
+        match self
+            .integer_index(index, "String")?
+            .and_then(|index| string.char_at(index))
+        {
+            Some(c) => Ok(c),
+            None => Err(self.runtime_error(
+                ErrorKind::IndexOutOfRange,
+                format!(
+                    "String index {} out of range for string of length {}.",
+                    index,
+                    string.char_count()
+                )
+                .as_str(),
+            )),
+        }
+    }
+
+    // The index as an i64, or None for integers too big for one, which are out of range
+    // for anything that can be indexed.
+    fn integer_index(&mut self, index: &Value, target: &str) -> Result<Option<i64>, LumiError> {
+        match index {
+            Value::Number(number) if number.as_big_int().is_some() => Ok(number.as_int()),
+            _ => Err(self.runtime_error(
+                ErrorKind::Type,
+                format!("{} index must be an integer.", target).as_str(),
+            )),
         }
     }
 
-    fn pop(&mut self) -> &FinalValue {
+    fn hash_key(&mut self, key: &Value) -> Result<HashKey, LumiError> {
+        Self::map_key(key).map_err(|message| self.runtime_error(ErrorKind::Type, &message))
+    }
+
+    pub fn map_key(key: &Value) -> Result<HashKey, String> {
+        key.hash_key()
+            .ok_or_else(|| "Map keys must be strings, numbers or booleans.".to_string())
+    }
+
+    // Replaces the instance on top of the stack with the named method bound to it.
+    fn bind_method(&mut self, class: ObjRef, name: ObjRef) -> Result<(), LumiError> {
+        let method = match class.as_obj() {
+            Obj::Class(obj_class) => obj_class.methods.get(name).cloned(),
+            _ => None,
+        };
+
+        match method {
+            Some(Value::Object(method)) => {
+                let receiver = self.peek(0).clone();
+                let bound = self.alloc(Obj::BoundMethod(ObjBoundMethod::new(receiver, method)));
+                self.pop();
+                self.push(Value::Object(bound))?;
+                Ok(())
+            }
+            _ => Err(self.runtime_error(
+                ErrorKind::UndefinedProperty,
+                format!("Undefined property '{}'.", name.as_obj()).as_str(),
+            )),
+        }
+    }
+
+    // The method closure sits on top of the stack with its class right below it.
+    fn define_method(&mut self, name: ObjRef) {
+        let method = self.peek(0).clone();
+        if let Value::Object(mut class) = self.peek(1) {
+            if let Obj::Class(obj_class) = class.as_obj_mut() {
+                obj_class.methods.set(name, method);
+            }
+        }
+        self.pop();
+    }
+
+    fn call(&mut self, closure: ObjRef, arg_count: usize) -> Result<(), LumiError> {
+        let function = match closure.as_obj() {
+            Obj::Closure(obj_closure) => obj_closure.function,
+            _ => unreachable!("Only closures can be called directly."),
+        };
+        let (arity, ip) = match function.as_obj() {
+            Obj::Function(obj_function) => (obj_function.arity, obj_function.chunk.code.as_ptr()),
+            _ => unreachable!("A closure always wraps a function."),
+        };
+
+        if arg_count != arity {
+            return Err(self.runtime_error(
+                ErrorKind::Arity,
+                format!("Expected {} arguments but got {}.", arity, arg_count).as_str(),
+            ));
+        }
+
+        if self.frames.len() == FRAMES_MAX {
+            return Err(self.runtime_error(ErrorKind::StackOverflow, "Stack overflow."));
+        }
+
+        self.frames.push(CallFrame {
+            closure,
+            ip,
+            slots: self.stack_top - arg_count - 1,
+        });
+        Ok(())
+    }
+
+    // Reuses the upvalue if another closure already captured this stack slot,
+    // so both closures keep sharing the variable once it is closed.
+    fn capture_upvalue(&mut self, location: usize) -> ObjRef {
+        let existing = self.open_upvalues.iter().find(|upvalue| {
+            matches!(upvalue.as_obj(), Obj::Upvalue(obj_upvalue) if obj_upvalue.location == location)
+        });
+        if let Some(upvalue) = existing {
+            return *upvalue;
+        }
+
+        let upvalue = self.alloc(Obj::Upvalue(ObjUpvalue::new(location)));
+        self.open_upvalues.push(upvalue);
+        upvalue
+    }
+
+    // Moves every captured stack slot at or above last off the stack and into its upvalue.
+    fn close_upvalues(&mut self, last: usize) {
+        let stack = &self.stack;
+        self.open_upvalues
+            .retain_mut(|upvalue| match upvalue.as_obj_mut() {
+                Obj::Upvalue(obj_upvalue) if obj_upvalue.location >= last => {
+                    obj_upvalue.closed = Some(stack[obj_upvalue.location].clone());
+                    false
+                }
+                _ => true,
+            });
+    }
+
+    // Deep recursion can fill the stack before it runs out of frames, when every frame
+    // holds a lot of temporaries.
+    fn push(&mut self, value: Value) -> Result<(), LumiError> {
+        if self.stack_top == STACK_MAX {
+            return Err(self.runtime_error(ErrorKind::StackOverflow, "Stack overflow."));
+        }
+        self.stack[self.stack_top] = value;
+        self.stack_top += 1;
+        Ok(())
+    }
+
+    fn pop(&mut self) -> &Value {
         self.stack_top -= 1;
-        &self.stack[self.stack_top as usize]
+        &self.stack[self.stack_top]
     }
 
-    fn peek(&mut self, distance: i32) -> &FinalValue {
-        if self.stack_top >= 1 + distance {
-            &self.stack[(self.stack_top - 1 - distance) as usize]
+    fn peek(&self, distance: usize) -> &Value {
+        if self.stack_top > distance {
+            &self.stack[self.stack_top - 1 - distance]
         } else {
             panic!("Stack is not big enough to peek so far.");
         }
@@ -337,59 +1026,104 @@ impl<'a> VM<'a> {
         value.is_nil() || (value.is_bool() && !value.as_bool().unwrap())
     }
 
-    fn concatenate(&mut self) {
+    fn concatenate(&mut self) -> Result<(), LumiError> {
         let b = self.pop().clone();
         let a = self.pop().clone();
 
-        let b_str = b.value.as_string_obj().unwrap().clone();
-        let a_str = a.value.as_string_obj().unwrap().clone();
+        let b_str = b.as_string_obj().unwrap();
+        let a_str = a.as_string_obj().unwrap();
 
-        let new_val = a_str.to_string() + &b_str.to_string();
-        let value = Value::Object(Box::new(Obj::String(ObjString::new(
-            new_val.as_bytes(),
-            new_val.as_bytes().len(),
-        ))));
-        self.push(FinalValue::default_with_value(value));
+        let new_val = a_str.to_string() + b_str.as_str();
+        let obj = self.intern(&new_val);
+        self.push(Value::Object(obj))
     }
 
     fn values_equal(&self, a: Value, b: Value) -> bool {
-        if !a.is_same_type(&b) {
+        Self::nested_values_equal(&a, &b, &mut Vec::new())
+    }
+
+    // The lists and maps being compared are kept in visiting. Meeting one of them again means
+    // it contains itself, so it's compared by identity instead of recursing forever.
+    fn nested_values_equal(a: &Value, b: &Value, visiting: &mut Vec<ObjRef>) -> bool {
+        if !a.is_same_type(b) {
             return false;
         }
         match a {
             Value::Number(_) => a == b,
             Value::Bool(_) => a == b,
-            Value::Object(ref obj) => match &**obj {
-                Obj::String(_) => a.as_c_string() == b.as_c_string(),
-                Obj::Function(obj_function) => todo!(),
-            },
+            Value::Object(obj) => {
+                if matches!(obj.as_obj(), Obj::List(_) | Obj::Map(_)) && visiting.contains(obj) {
+                    return a == b;
+                }
+                visiting.push(*obj);
+                let equal = match obj.as_obj() {
+                    // Lists are equal when they hold equal items in the same order.
+                    Obj::List(a_list) => match b.as_list() {
+                        Some(b_list) => {
+                            a_list.items.len() == b_list.items.len()
+                                && a_list
+                                    .items
+                                    .iter()
+                                    .zip(b_list.items.iter())
+                                    .all(|(x, y)| Self::nested_values_equal(x, y, visiting))
+                        }
+                        None => false,
+                    },
+                    // Maps are equal when they hold the same keys with equal values.
+                    Obj::Map(a_map) => match b.as_map() {
+                        Some(b_map) => {
+                            a_map.len() == b_map.len()
+                                && a_map.iter().all(|(key, value)| {
+                                    let other = key.hash_key().and_then(|k| b_map.get(&k));
+                                    other.is_some_and(|other| {
+                                        Self::nested_values_equal(value, other, visiting)
+                                    })
+                                })
+                        }
+                        None => false,
+                    },
+                    // Strings are interned, equal strings are the same object.
+                    Obj::String(_)
+                    | Obj::Function(_)
+                    | Obj::Closure(_)
+                    | Obj::Upvalue(_)
+                    | Obj::Class(_)
+                    | Obj::Instance(_)
+                    | Obj::BoundMethod(_)
+                    | Obj::Native(_) => a == b,
+                };
+                visiting.pop();
+                equal
+            }
             Value::Nil => a == b,
         }
     }
 
-    fn var_final_error(&mut self, final_val: &FinalValue) -> InterpretResult {
+    fn final_error(&mut self, name: ObjRef, action: &str) -> LumiError {
         self.runtime_error(
+            ErrorKind::Final,
             format!(
-                "Variable '{}' is final and cannot be modified.",
-                final_val.value
+                "Variable '{}' is final and cannot be {}.",
+                name.as_obj(),
+                action
             )
             .as_str(),
-        );
-        return InterpretResult::InterpretRuntimeError;
+        )
     }
 }
 
 #[cfg(feature = "trace_exec")]
 fn trace_execution(vm: &VM) {
     print!("    ");
-    for slot in &vm.stack[0..vm.stack_top as usize] {
+    for slot in &vm.stack[0..vm.stack_top] {
         print!("[ ");
-        print!("{}", *slot);
+        print!("{}", slot);
         print!(" ]");
     }
     println!();
+    let frame = vm.frame();
     disassemble_instruction(
-        &vm.compiler.chunk,
-        vm.ip as usize - vm.compiler.chunk.code.as_ptr() as usize,
+        frame.chunk(),
+        frame.ip as usize - frame.chunk().code.as_ptr() as usize,
     );
 }